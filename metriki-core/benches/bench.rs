@@ -51,3 +51,65 @@ fn bench_multithread(b: &mut Bencher) {
         pool.join();
     });
 }
+
+fn histogram_contention(rg: std::sync::Arc<MetricsRegistry>, b: &mut Bencher) {
+    let pool = ThreadPool::new(32);
+
+    b.iter(|| {
+        for _ in 0..32 {
+            let rg2 = rg.clone();
+            pool.execute(move || {
+                let histogram = rg2.histogram("test.histogram");
+                for i in 0..1000 {
+                    histogram.update(i);
+                }
+            });
+        }
+        pool.join();
+    });
+}
+
+#[bench]
+fn bench_histogram_contention(b: &mut Bencher) {
+    let rg = MetricsRegistry::arc();
+    histogram_contention(rg, b);
+}
+
+#[bench]
+fn bench_striped_histogram_contention(b: &mut Bencher) {
+    let rg = MetricsRegistry::arc();
+    rg.set_histogram_stripes(32);
+    histogram_contention(rg, b);
+}
+
+fn counter_contention(rg: std::sync::Arc<MetricsRegistry>, b: &mut Bencher) {
+    let pool = ThreadPool::new(32);
+
+    b.iter(|| {
+        for _ in 0..32 {
+            let rg2 = rg.clone();
+            pool.execute(move || {
+                let counter = rg2.counter("test.counter");
+                let meter = rg2.meter("test.meter");
+                for _ in 0..1000 {
+                    counter.inc(1);
+                    meter.mark();
+                }
+            });
+        }
+        pool.join();
+    });
+}
+
+#[bench]
+fn bench_counter_contention(b: &mut Bencher) {
+    let rg = MetricsRegistry::arc();
+    counter_contention(rg, b);
+}
+
+#[bench]
+fn bench_striped_counter_contention(b: &mut Bencher) {
+    let rg = MetricsRegistry::arc();
+    rg.set_counter_stripes(32);
+    counter_contention(rg, b);
+}
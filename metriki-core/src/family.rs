@@ -0,0 +1,161 @@
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use crate::key::{Key, Tag};
+use crate::metrics::{Counter, Histogram, Meter, MonotonicCounter, Timer};
+use crate::registry::Inner;
+
+/// A group of metrics that share the same name and label keys.
+///
+/// Each distinct combination of label values is a child metric, which
+/// is registered in the registry as a tagged metric. Children are looked up
+/// from the registry on each `with` call, so the family never holds a metric
+/// the registry has replaced.
+///
+/// ```
+/// use metriki_core::MetricsRegistry;
+///
+/// let registry = MetricsRegistry::new();
+/// let requests = registry.counter_family("http_requests", &["method", "status"]);
+///
+/// requests.with(&["GET", "200"]).inc(1);
+/// ```
+pub struct MetricFamily<M> {
+    name: String,
+    label_keys: Vec<String>,
+    registry: Arc<Inner>,
+    factory: fn(&Inner, Key) -> Arc<M>,
+}
+
+/// A family of `Counter`s.
+pub type CounterFamily = MetricFamily<Counter>;
+/// A family of `MonotonicCounter`s.
+pub type MonotonicCounterFamily = MetricFamily<MonotonicCounter>;
+/// A family of `Meter`s.
+pub type MeterFamily = MetricFamily<Meter>;
+/// A family of `Histogram`s.
+pub type HistogramFamily = MetricFamily<Histogram>;
+/// A family of `Timer`s.
+pub type TimerFamily = MetricFamily<Timer>;
+
+impl<M> MetricFamily<M> {
+    pub(crate) fn new(
+        registry: Arc<Inner>,
+        name: &str,
+        label_keys: &[&str],
+        factory: fn(&Inner, Key) -> Arc<M>,
+    ) -> MetricFamily<M> {
+        MetricFamily {
+            name: name.to_owned(),
+            label_keys: label_keys.iter().map(|k| (*k).to_owned()).collect(),
+            registry,
+            factory,
+        }
+    }
+
+    /// Return the child metric for given label values, create if not found.
+    ///
+    /// Values are matched to label keys by position.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the number of values doesn't match the number
+    /// of label keys, or a metric with same name, tags and different type is
+    /// already registered.
+    pub fn with(&self, values: &[&str]) -> Arc<M> {
+        assert_eq!(
+            self.label_keys.len(),
+            values.len(),
+            "Label values don't match label keys of metric family {}.",
+            self.name
+        );
+
+        let tags = self
+            .label_keys
+            .iter()
+            .zip(values.iter())
+            .map(|(k, v)| Tag::new(k, v))
+            .collect();
+        (self.factory)(&self.registry, Key::from(&self.name, tags))
+    }
+
+    /// Name of metrics in this family.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Label keys of this family.
+    pub fn label_keys(&self) -> &[String] {
+        self.label_keys.as_slice()
+    }
+}
+
+impl<M> Debug for MetricFamily<M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("MetricFamily")
+            .field("name", &self.name)
+            .field("label_keys", &self.label_keys)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::key::{Key, Tag};
+    use crate::registry::MetricsRegistry;
+
+    #[test]
+    fn test_counter_family() {
+        let registry = MetricsRegistry::new();
+        let family = registry.counter_family("http_requests", &["method", "status"]);
+
+        family.with(&["GET", "200"]).inc(1);
+        family.with(&["GET", "200"]).inc(1);
+        family.with(&["POST", "500"]).inc(1);
+
+        assert!(Arc::ptr_eq(
+            &family.with(&["GET", "200"]),
+            &registry.counter_with_tags(
+                "http_requests",
+                vec![Tag::new("method", "GET"), Tag::new("status", "200")]
+            )
+        ));
+
+        let snapshots = registry.snapshots();
+        assert_eq!(2, snapshots.len());
+
+        let key = Key::from(
+            "http_requests",
+            vec![Tag::new("method", "GET"), Tag::new("status", "200")],
+        );
+        let counter = snapshots.get(&key).and_then(|m| m.as_counter()).unwrap();
+        assert_eq!(2, counter.value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_family_child_replaced() {
+        let registry = MetricsRegistry::new();
+        let family = registry.counter_family("connections", &["pool"]);
+        family.with(&["db"]).inc(1);
+
+        // the counter is no longer registered, family must not keep updating it
+        registry.gauge_with_tags(
+            "connections",
+            vec![Tag::new("pool", "db")],
+            Box::new(|| 1.0),
+        );
+        family.with(&["db"]).inc(1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_family_label_mismatch() {
+        let registry = MetricsRegistry::new();
+        let family = registry.timer_family("latency", &["method"]);
+
+        family.with(&["GET", "200"]);
+    }
+}
@@ -15,6 +15,7 @@
 //! * Counter: a value can be increased and decreased
 //! * Gauge: a function that reports a value when it is called
 //! * MetricsSet: a trait to be implemented and to give dynamic metrics when called by registry
+//! * TopK: a metrics set that tracks the heaviest items, like top endpoints, in fixed memory
 //!
 //! ## Ecosystem
 //!
@@ -90,17 +91,25 @@
 //!
 //!
 
+mod family;
 mod filter;
 pub mod global;
 pub mod key;
 pub mod metrics;
 mod mset;
 mod registry;
+pub mod reporter;
+pub mod reporting;
+mod topk;
 mod utils;
 
+pub use family::{
+    CounterFamily, HistogramFamily, MeterFamily, MetricFamily, MonotonicCounterFamily, TimerFamily,
+};
 pub use filter::MetricsFilter;
 pub use mset::MetricsSet;
 pub use registry::MetricsRegistry;
+pub use topk::TopK;
 
 #[cfg(feature = "macros")]
 #[cfg_attr(docsrs, doc(cfg(feature = "macros")))]
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::utils;
+
+/// A counter that spreads increments over cache padded cells.
+///
+/// Like Java's `LongAdder`, threads update different cells so they
+/// don't contend on a single cache line. Cells are summed on read.
+///
+/// Values are wrapping, so signed numbers can be added with two's
+/// complement.
+#[derive(Debug)]
+pub(crate) enum LongAdder {
+    Single(AtomicU64),
+    Striped(Box<[CachePadded<AtomicU64>]>),
+}
+
+impl LongAdder {
+    /// Create an adder with given number of cells. A value of 0 or 1 uses
+    /// a single atomic without padding.
+    pub(crate) fn new(stripes: usize) -> LongAdder {
+        if stripes > 1 {
+            LongAdder::Striped(
+                (0..stripes)
+                    .map(|_| CachePadded::new(AtomicU64::new(0)))
+                    .collect(),
+            )
+        } else {
+            LongAdder::Single(AtomicU64::new(0))
+        }
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        match self {
+            LongAdder::Single(v) => v.fetch_add(n, Ordering::Relaxed),
+            LongAdder::Striped(cells) => {
+                cells[utils::thread_stripe() % cells.len()].fetch_add(n, Ordering::Relaxed)
+            }
+        };
+    }
+
+    pub(crate) fn sum(&self) -> u64 {
+        match self {
+            LongAdder::Single(v) => v.load(Ordering::Relaxed),
+            LongAdder::Striped(cells) => cells
+                .iter()
+                .fold(0, |acc, c| acc.wrapping_add(c.load(Ordering::Relaxed))),
+        }
+    }
+
+    /// Returns the sum and reset the adder to zero.
+    pub(crate) fn take(&self) -> u64 {
+        match self {
+            LongAdder::Single(v) => v.swap(0, Ordering::Relaxed),
+            LongAdder::Striped(cells) => cells
+                .iter()
+                .fold(0, |acc, c| acc.wrapping_add(c.swap(0, Ordering::Relaxed))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::LongAdder;
+
+    #[test]
+    fn test_striped_adder() {
+        let adder = Arc::new(LongAdder::new(4));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let adder = adder.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        adder.add(1);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(8000, adder.sum());
+        adder.add(2u64.wrapping_neg());
+        assert_eq!(7998, adder.take());
+        assert_eq!(0, adder.sum());
+    }
+}
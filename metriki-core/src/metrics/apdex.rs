@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+#[cfg(feature = "ser")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "ser")]
+use serde::{Serialize, Serializer};
+
+use super::adder::LongAdder;
+
+/// Apdex tracks user satisfaction of response time, against a threshold `T`.
+///
+/// Samples up to `T` are satisfied, up to `4T` are tolerating, and slower
+/// ones or errors are frustrated. The score is
+/// `(satisfied + tolerating / 2) / total`, in range of `[0, 1]`.
+///
+/// Counts are kept since creation.
+#[derive(Debug)]
+pub struct Apdex {
+    threshold: Duration,
+    // `4T`, saturated for large thresholds
+    tolerating_threshold: Duration,
+    satisfied: LongAdder,
+    tolerating: LongAdder,
+    frustrated: LongAdder,
+}
+
+impl Apdex {
+    pub(crate) fn new(threshold: Duration) -> Apdex {
+        Apdex {
+            threshold,
+            tolerating_threshold: threshold.checked_mul(4).unwrap_or(Duration::MAX),
+            satisfied: LongAdder::new(1),
+            tolerating: LongAdder::new(1),
+            frustrated: LongAdder::new(1),
+        }
+    }
+
+    /// Classify a response time.
+    pub fn update(&self, duration: Duration) {
+        if duration <= self.threshold {
+            self.satisfied.add(1);
+        } else if duration <= self.tolerating_threshold {
+            self.tolerating.add(1);
+        } else {
+            self.frustrated.add(1);
+        }
+    }
+
+    /// Count a failed request, which is always frustrated.
+    pub fn mark_frustrated(&self) {
+        self.frustrated.add(1);
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    pub fn score(&self) -> f64 {
+        self.snapshot().score()
+    }
+
+    pub fn snapshot(&self) -> ApdexSnapshot {
+        ApdexSnapshot {
+            satisfied: self.satisfied.sum(),
+            tolerating: self.tolerating.sum(),
+            frustrated: self.frustrated.sum(),
+        }
+    }
+}
+
+/// Counts of an `Apdex` at some point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApdexSnapshot {
+    satisfied: u64,
+    tolerating: u64,
+    frustrated: u64,
+}
+
+impl ApdexSnapshot {
+    pub fn satisfied(&self) -> u64 {
+        self.satisfied
+    }
+
+    pub fn tolerating(&self) -> u64 {
+        self.tolerating
+    }
+
+    pub fn frustrated(&self) -> u64 {
+        self.frustrated
+    }
+
+    pub fn count(&self) -> u64 {
+        self.satisfied + self.tolerating + self.frustrated
+    }
+
+    /// The Apdex score. It's 1 when there is no sample, as no one is
+    /// frustrated.
+    pub fn score(&self) -> f64 {
+        let count = self.count();
+        if count == 0 {
+            1f64
+        } else {
+            (self.satisfied as f64 + self.tolerating as f64 / 2f64) / count as f64
+        }
+    }
+
+    /// Subtract counts of an earlier snapshot of the same metric.
+    ///
+    /// If counts are lower than `earlier`, the metric is considered reset and
+    /// this snapshot is returned as is.
+    pub fn delta(&self, earlier: &ApdexSnapshot) -> ApdexSnapshot {
+        if self.satisfied < earlier.satisfied
+            || self.tolerating < earlier.tolerating
+            || self.frustrated < earlier.frustrated
+        {
+            *self
+        } else {
+            ApdexSnapshot {
+                satisfied: self.satisfied - earlier.satisfied,
+                tolerating: self.tolerating - earlier.tolerating,
+                frustrated: self.frustrated - earlier.frustrated,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ser")]
+impl Serialize for Apdex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let snapshot = self.snapshot();
+        let mut map = serializer.serialize_map(Some(4))?;
+
+        map.serialize_entry("score", &snapshot.score())?;
+        map.serialize_entry("satisfied", &snapshot.satisfied())?;
+        map.serialize_entry("tolerating", &snapshot.tolerating())?;
+        map.serialize_entry("frustrated", &snapshot.frustrated())?;
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Apdex;
+
+    #[test]
+    fn test_apdex() {
+        let apdex = Apdex::new(Duration::from_millis(100));
+        assert_eq!(1.0, apdex.score());
+
+        apdex.update(Duration::from_millis(50));
+        apdex.update(Duration::from_millis(100));
+        apdex.update(Duration::from_millis(300));
+        apdex.update(Duration::from_millis(500));
+        apdex.mark_frustrated();
+
+        let snapshot = apdex.snapshot();
+        assert_eq!(2, snapshot.satisfied());
+        assert_eq!(1, snapshot.tolerating());
+        assert_eq!(2, snapshot.frustrated());
+        assert_eq!(0.5, snapshot.score());
+
+        apdex.update(Duration::from_millis(10));
+        assert_eq!(1.0, apdex.snapshot().delta(&snapshot).score());
+    }
+
+    #[test]
+    fn test_large_threshold() {
+        let apdex = Apdex::new(Duration::MAX / 2);
+        apdex.update(Duration::MAX);
+        assert_eq!(1, apdex.snapshot().tolerating());
+    }
+}
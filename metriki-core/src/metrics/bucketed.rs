@@ -0,0 +1,309 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[cfg(feature = "ser")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "ser")]
+use serde::{Serialize, Serializer};
+
+use super::Exemplar;
+
+/// Upper bounds of buckets in a `BucketedHistogram`.
+///
+/// A sample is counted in the first bucket whose upper bound is greater
+/// than or equal to it. Samples larger than the last bound go to an
+/// implicit `+Inf` bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets {
+    bounds: Vec<f64>,
+}
+
+impl Buckets {
+    /// Create buckets with given upper bounds.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if bounds are not finite and strictly increasing.
+    pub fn new(bounds: Vec<f64>) -> Buckets {
+        assert!(
+            bounds.iter().all(|b| b.is_finite()),
+            "Bucket bounds must be finite."
+        );
+        assert!(
+            bounds.windows(2).all(|w| w[0] < w[1]),
+            "Bucket bounds must be strictly increasing."
+        );
+
+        Buckets { bounds }
+    }
+
+    /// Create `count` buckets, the first upper bound is `start` and each next
+    /// bound is `width` larger.
+    pub fn linear(start: f64, width: f64, count: usize) -> Buckets {
+        Buckets::new((0..count).map(|i| start + width * i as f64).collect())
+    }
+
+    /// Create `count` buckets, the first upper bound is `start` and each next
+    /// bound is multiplied by `factor`.
+    pub fn exponential(start: f64, factor: f64, count: usize) -> Buckets {
+        Buckets::new((0..count).map(|i| start * factor.powi(i as i32)).collect())
+    }
+
+    pub fn bounds(&self) -> &[f64] {
+        self.bounds.as_slice()
+    }
+}
+
+/// Histograms that count samples into fixed buckets.
+///
+/// Unlike `Histogram`, which tracks quantiles of recent samples, bucket
+/// counts are kept since creation and can be aggregated across instances.
+/// Prometheus exporter reports it as `histogram` type, with exemplars of
+/// buckets in OpenMetrics format.
+#[derive(Debug)]
+pub struct BucketedHistogram {
+    buckets: Buckets,
+    counts: Box<[AtomicU64]>,
+    sum: AtomicU64,
+    exemplars: Mutex<Vec<Option<Exemplar>>>,
+}
+
+impl BucketedHistogram {
+    pub(crate) fn new(buckets: Buckets) -> BucketedHistogram {
+        BucketedHistogram {
+            counts: (0..=buckets.bounds.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            buckets,
+            sum: AtomicU64::new(0f64.to_bits()),
+            exemplars: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a sample value.
+    ///
+    /// Non-finite values, NaN and infinities, are ignored as they would
+    /// poison the sum.
+    pub fn update(&self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        self.counts[self.index(value)].fetch_add(1, Ordering::Relaxed);
+
+        // f64 has no atomic add, so update its bits in a cas loop
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Record a sample value with an exemplar that links it to a trace.
+    ///
+    /// The latest exemplar of each bucket is kept.
+    pub fn update_with_exemplar(&self, value: f64, exemplar: Exemplar) {
+        if !value.is_finite() {
+            return;
+        }
+
+        self.update(value);
+        let mut exemplars = self.exemplars.lock().unwrap();
+        if exemplars.is_empty() {
+            exemplars.resize(self.counts.len(), None);
+        }
+        exemplars[self.index(value)] = Some(exemplar.recorded(value));
+    }
+
+    fn index(&self, value: f64) -> usize {
+        self.buckets.bounds.partition_point(|b| *b < value)
+    }
+
+    pub fn buckets(&self) -> &Buckets {
+        &self.buckets
+    }
+
+    pub fn snapshot(&self) -> BucketedHistogramSnapshot {
+        BucketedHistogramSnapshot {
+            bounds: self.buckets.bounds.clone(),
+            counts: self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+            sum: f64::from_bits(self.sum.load(Ordering::Relaxed)),
+            exemplars: self.exemplars.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Bucket counts of a `BucketedHistogram` at some point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketedHistogramSnapshot {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+impl BucketedHistogramSnapshot {
+    /// Upper bounds of buckets, not including the `+Inf` bucket.
+    pub fn bounds(&self) -> &[f64] {
+        self.bounds.as_slice()
+    }
+
+    /// Number of samples in each bucket, the last one is the `+Inf` bucket.
+    ///
+    /// Counts are not cumulative, as used by OpenTelemetry explicit bucket
+    /// histograms.
+    pub fn counts(&self) -> &[u64] {
+        self.counts.as_slice()
+    }
+
+    /// Iterate buckets as `(upper bound, cumulative count)`, as used by
+    /// Prometheus. The last item has upper bound of `f64::INFINITY`.
+    pub fn iter_cumulative(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(self.counts.iter().scan(0u64, |acc, c| {
+                *acc += c;
+                Some(*acc)
+            }))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Iterate latest exemplars of buckets, as `(upper bound, exemplar)`.
+    /// The upper bound of the last bucket is `f64::INFINITY`.
+    pub fn iter_exemplars(&self) -> impl Iterator<Item = (f64, &Exemplar)> + '_ {
+        self.bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(self.exemplars.iter())
+            .filter_map(|(le, e)| e.as_ref().map(|e| (le, e)))
+    }
+
+    /// Subtract counts of an earlier snapshot of the same histogram.
+    ///
+    /// If bounds are different or counts are lower than `earlier`, the
+    /// histogram is considered reset and this snapshot is returned as is.
+    pub fn delta(&self, earlier: &BucketedHistogramSnapshot) -> BucketedHistogramSnapshot {
+        if self.bounds != earlier.bounds
+            || self
+                .counts
+                .iter()
+                .zip(earlier.counts.iter())
+                .any(|(c, e)| c < e)
+        {
+            return self.clone();
+        }
+
+        BucketedHistogramSnapshot {
+            bounds: self.bounds.clone(),
+            counts: self
+                .counts
+                .iter()
+                .zip(earlier.counts.iter())
+                .map(|(c, e)| c - e)
+                .collect(),
+            sum: self.sum - earlier.sum,
+            exemplars: self.exemplars.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "ser")]
+impl Serialize for BucketedHistogram {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let snapshot = self.snapshot();
+        let mut map = serializer.serialize_map(Some(snapshot.counts.len() + 2))?;
+
+        map.serialize_entry("count", &snapshot.count())?;
+        map.serialize_entry("sum", &snapshot.sum())?;
+        for (le, count) in snapshot.iter_cumulative() {
+            map.serialize_entry(&format!("le_{}", le), &count)?;
+        }
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BucketedHistogram, Buckets};
+    use crate::metrics::Exemplar;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(
+            &[1.0, 2.0, 4.0, 8.0],
+            Buckets::exponential(1.0, 2.0, 4).bounds()
+        );
+        assert_eq!(&[5.0, 15.0, 25.0], Buckets::linear(5.0, 10.0, 3).bounds());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unordered_buckets() {
+        Buckets::new(vec![1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_bucketed_histogram() {
+        let histogram = BucketedHistogram::new(Buckets::new(vec![1.0, 10.0]));
+
+        histogram.update(0.5);
+        histogram.update(1.0);
+        histogram.update(5.0);
+        histogram.update(100.0);
+        histogram.update(f64::NAN);
+        histogram.update(f64::INFINITY);
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(&[2, 1, 1], snapshot.counts());
+        assert_eq!(4, snapshot.count());
+        assert_eq!(106.5, snapshot.sum());
+        assert_eq!(
+            vec![(1.0, 2), (10.0, 3), (f64::INFINITY, 4)],
+            snapshot.iter_cumulative().collect::<Vec<(f64, u64)>>()
+        );
+
+        histogram.update(2.0);
+        let delta = histogram.snapshot().delta(&snapshot);
+        assert_eq!(&[0, 1, 0], delta.counts());
+        assert_eq!(2.0, delta.sum());
+    }
+
+    #[test]
+    fn test_bucketed_histogram_exemplars() {
+        let histogram = BucketedHistogram::new(Buckets::new(vec![1.0, 10.0]));
+
+        histogram.update_with_exemplar(5.0, Exemplar::new("a", vec![]));
+        histogram.update_with_exemplar(8.0, Exemplar::new("b", vec![]));
+        histogram.update_with_exemplar(20.0, Exemplar::new("c", vec![]));
+        histogram.update_with_exemplar(f64::NAN, Exemplar::new("d", vec![]));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(3, snapshot.count());
+        let exemplars: Vec<(f64, &str, f64)> = snapshot
+            .iter_exemplars()
+            .map(|(le, e)| (le, e.trace_id(), e.value()))
+            .collect();
+        assert_eq!(
+            vec![(10.0, "b", 8.0), (f64::INFINITY, "c", 20.0)],
+            exemplars
+        );
+    }
+}
@@ -1,33 +1,41 @@
-use std::sync::atomic::{AtomicI64, Ordering};
-
 #[cfg(feature = "ser")]
 use serde::ser::SerializeMap;
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
+use super::adder::LongAdder;
+
 /// Counters are integer values you can increment and decrement.
+///
+/// As a counter may go down, reporters treat it as a gauge. Use
+/// `MonotonicCounter` for totals that only go up.
 #[derive(Debug)]
 pub struct Counter {
-    value: AtomicI64,
+    value: LongAdder,
 }
 
 impl Counter {
     pub(crate) fn new() -> Counter {
+        Counter::with_stripes(1)
+    }
+
+    /// Create a counter that spreads increments over given number of stripes.
+    pub(crate) fn with_stripes(stripes: usize) -> Counter {
         Counter {
-            value: AtomicI64::new(0),
+            value: LongAdder::new(stripes),
         }
     }
 
     pub fn inc(&self, n: i64) {
-        self.value.fetch_add(n, Ordering::Relaxed);
+        self.value.add(n as u64);
     }
 
     pub fn dec(&self, n: i64) {
-        self.value.fetch_sub(n, Ordering::Relaxed);
+        self.value.add((n as u64).wrapping_neg());
     }
 
     pub fn value(&self) -> i64 {
-        self.value.load(Ordering::Relaxed)
+        self.value.sum() as i64
     }
 }
 
@@ -0,0 +1,140 @@
+use std::time::SystemTime;
+
+#[cfg(feature = "ser")]
+use serde::Serialize;
+
+use super::histogram::DEFAULT_RANGE_MAX;
+use crate::key::Tag;
+
+/// Number of exemplar slots kept by a histogram, one for each power of two
+/// up to the largest value recorded.
+const EXEMPLAR_SLOTS: usize = slot(DEFAULT_RANGE_MAX) + 1;
+
+const fn slot(value: u64) -> usize {
+    (u64::BITS - value.leading_zeros()) as usize
+}
+
+/// Exemplars link a sample of histogram or timer to a trace.
+///
+/// The value and timestamp of the exemplar are set when it's recorded
+/// with `Histogram::update_with_exemplar`, `BucketedHistogram::update_with_exemplar`
+/// or `TimerContext::stop_with_exemplar`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "ser", derive(Serialize))]
+pub struct Exemplar {
+    trace_id: String,
+    labels: Vec<Tag>,
+    value: f64,
+    timestamp: SystemTime,
+}
+
+impl Exemplar {
+    /// Create an exemplar with given trace id and additional labels.
+    pub fn new(trace_id: &str, labels: Vec<Tag>) -> Exemplar {
+        Exemplar {
+            trace_id: trace_id.to_owned(),
+            labels,
+            value: 0.0,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn trace_id(&self) -> &str {
+        self.trace_id.as_str()
+    }
+
+    pub fn labels(&self) -> &[Tag] {
+        self.labels.as_slice()
+    }
+
+    /// The sample value this exemplar was recorded with.
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// The time this exemplar was recorded.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Set the value and timestamp when it's recorded.
+    pub(crate) fn recorded(mut self, value: f64) -> Exemplar {
+        self.value = value;
+        self.timestamp = SystemTime::now();
+        self
+    }
+}
+
+/// A bounded store of recent exemplars.
+///
+/// Samples are grouped into exponential buckets by their value, only
+/// the latest exemplar of each bucket is kept.
+#[derive(Debug, Default)]
+pub(crate) struct ExemplarReservoir {
+    slots: Vec<Option<Exemplar>>,
+}
+
+impl ExemplarReservoir {
+    pub(crate) fn record(&mut self, value: u64, exemplar: Exemplar) {
+        self.offer(exemplar.recorded(value as f64));
+    }
+
+    /// Keep a recorded exemplar if it's newer than the one in its bucket.
+    pub(crate) fn offer(&mut self, exemplar: Exemplar) {
+        if self.slots.is_empty() {
+            self.slots.resize(EXEMPLAR_SLOTS, None);
+        }
+
+        let index = slot(exemplar.value as u64).min(EXEMPLAR_SLOTS - 1);
+        let slot = &mut self.slots[index];
+        if slot
+            .as_ref()
+            .map(|e| e.timestamp <= exemplar.timestamp)
+            .unwrap_or(true)
+        {
+            *slot = Some(exemplar);
+        }
+    }
+
+    /// All exemplars ordered by value.
+    pub(crate) fn exemplars(&self) -> Vec<Exemplar> {
+        self.slots.iter().flatten().cloned().collect()
+    }
+
+    /// Take all exemplars ordered by value and reset the reservoir.
+    pub(crate) fn take(&mut self) -> Vec<Exemplar> {
+        std::mem::take(&mut self.slots)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Exemplar, ExemplarReservoir, EXEMPLAR_SLOTS};
+
+    #[test]
+    fn test_exemplar_reservoir() {
+        let mut reservoir = ExemplarReservoir::default();
+
+        reservoir.record(3, Exemplar::new("a", vec![]));
+        reservoir.record(2, Exemplar::new("b", vec![]));
+        reservoir.record(100, Exemplar::new("c", vec![]));
+        reservoir.record(0, Exemplar::new("d", vec![]));
+        reservoir.record(u64::MAX, Exemplar::new("e", vec![]));
+
+        let exemplars = reservoir.take();
+        assert_eq!(18, EXEMPLAR_SLOTS);
+        assert_eq!(4, exemplars.len());
+        assert_eq!(vec!["d", "b", "c", "e"], {
+            exemplars
+                .iter()
+                .map(|e| e.trace_id())
+                .collect::<Vec<&str>>()
+        });
+        assert_eq!(2.0, exemplars[1].value());
+
+        assert!(reservoir.take().is_empty());
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[cfg(feature = "ser")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "ser")]
+use serde::{Serialize, Serializer};
+
+/// Smallest scale of exponential histograms, each bucket is `2^16` times
+/// larger than the previous one.
+pub const MIN_SCALE: i32 = -4;
+/// Largest scale of exponential histograms, each bucket is `2^(1/256)`
+/// times larger than the previous one.
+pub const MAX_SCALE: i32 = 8;
+
+/// Default number of buckets of each sign, before the histogram is
+/// downscaled to fit.
+const DEFAULT_MAX_BUCKETS: usize = 160;
+/// Default width of the zero bucket, same as Prometheus client libraries.
+const DEFAULT_ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+/// Histograms with base-2 exponential buckets.
+///
+/// The bucket layout is defined by `scale`: bucket `i` counts values in
+/// `(base^i, base^(i+1)]` where `base = 2^(2^-scale)`. Only buckets with
+/// samples are stored. When samples spread over too many buckets, the
+/// histogram is downscaled, merging every two adjacent buckets into one.
+///
+/// This is the layout of Prometheus native histograms and OpenTelemetry
+/// exponential histograms. Like `BucketedHistogram`, counts are kept since
+/// creation.
+#[derive(Debug)]
+pub struct ExponentialHistogram {
+    inner: Mutex<ExponentialHistogramSnapshot>,
+}
+
+impl ExponentialHistogram {
+    /// Create a histogram with initial scale.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if scale is not in `MIN_SCALE..=MAX_SCALE`.
+    pub(crate) fn new(scale: i32) -> ExponentialHistogram {
+        assert!(
+            (MIN_SCALE..=MAX_SCALE).contains(&scale),
+            "Scale of exponential histogram must be in [{}, {}].",
+            MIN_SCALE,
+            MAX_SCALE
+        );
+
+        ExponentialHistogram {
+            inner: Mutex::new(ExponentialHistogramSnapshot::new(scale)),
+        }
+    }
+
+    /// Record a sample value.
+    ///
+    /// Non-finite values, NaN and infinities, are ignored as they would
+    /// poison the sum and force the histogram down to `MIN_SCALE`.
+    pub fn update(&self, value: f64) {
+        self.inner.lock().unwrap().record(value);
+    }
+
+    /// Current scale, which may be lower than the initial scale.
+    pub fn scale(&self) -> i32 {
+        self.inner.lock().unwrap().scale
+    }
+
+    pub fn snapshot(&self) -> ExponentialHistogramSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+/// Bucket counts of an `ExponentialHistogram` at some point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExponentialHistogramSnapshot {
+    scale: i32,
+    max_buckets: usize,
+    zero_threshold: f64,
+    zero_count: u64,
+    count: u64,
+    sum: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl ExponentialHistogramSnapshot {
+    fn new(scale: i32) -> ExponentialHistogramSnapshot {
+        ExponentialHistogramSnapshot {
+            scale,
+            max_buckets: DEFAULT_MAX_BUCKETS,
+            zero_threshold: DEFAULT_ZERO_THRESHOLD,
+            zero_count: 0,
+            count: 0,
+            sum: 0f64,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        self.count += 1;
+        self.sum += value;
+
+        if value.abs() <= self.zero_threshold {
+            self.zero_count += 1;
+            return;
+        }
+
+        let buckets = if value > 0f64 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *buckets
+            .entry(bucket_index(value.abs(), self.scale))
+            .or_insert(0) += 1;
+
+        self.fit_max_buckets();
+    }
+
+    fn fit_max_buckets(&mut self) {
+        while self.scale > MIN_SCALE
+            && (span_len(&self.positive) > self.max_buckets
+                || span_len(&self.negative) > self.max_buckets)
+        {
+            self.downscale(1);
+        }
+    }
+
+    pub fn scale(&self) -> i32 {
+        self.scale
+    }
+
+    /// Values with absolute value up to zero threshold are counted in the
+    /// zero bucket.
+    pub fn zero_threshold(&self) -> f64 {
+        self.zero_threshold
+    }
+
+    pub fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Iterate buckets of positive values as `(index, count)`, ordered by index.
+    pub fn positive_buckets(&self) -> impl Iterator<Item = (i32, u64)> + '_ {
+        self.positive.iter().map(|(i, c)| (*i, *c))
+    }
+
+    /// Iterate buckets of negative values as `(index, count)`, ordered by
+    /// index. Bucket `i` counts values in `[-base^(i+1), -base^i)`.
+    pub fn negative_buckets(&self) -> impl Iterator<Item = (i32, u64)> + '_ {
+        self.negative.iter().map(|(i, c)| (*i, *c))
+    }
+
+    /// Lower (exclusive) and upper (inclusive) bound of positive bucket at
+    /// `index`.
+    pub fn bucket_bounds(&self, index: i32) -> (f64, f64) {
+        (
+            bucket_lower_bound(index, self.scale),
+            bucket_lower_bound(index + 1, self.scale),
+        )
+    }
+
+    /// Estimate value at quantile, from the log-scale midpoint of the
+    /// bucket it falls in.
+    pub fn quantile(&self, quantile: f64) -> f64 {
+        if self.count == 0 {
+            return 0f64;
+        }
+
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let midpoint = |i: i32| 2f64.powf((i as f64 + 0.5) * 2f64.powi(-self.scale));
+
+        let mut seen = 0;
+        for (i, c) in self.negative.iter().rev() {
+            seen += c;
+            if seen >= rank {
+                return -midpoint(*i);
+            }
+        }
+        seen += self.zero_count;
+        if seen >= rank {
+            return 0f64;
+        }
+        for (i, c) in self.positive.iter() {
+            seen += c;
+            if seen >= rank {
+                return midpoint(*i);
+            }
+        }
+
+        self.positive
+            .keys()
+            .next_back()
+            .map(|i| midpoint(*i))
+            .unwrap_or(0f64)
+    }
+
+    /// Reduce scale by `by`, each new bucket counts `2^by` old buckets.
+    ///
+    /// The scale won't go below `MIN_SCALE`.
+    pub fn downscale(&mut self, by: u32) {
+        let by = by.min((self.scale - MIN_SCALE) as u32);
+        if by == 0 {
+            return;
+        }
+
+        for buckets in [&mut self.positive, &mut self.negative] {
+            let old = std::mem::take(buckets);
+            for (i, c) in old {
+                *buckets.entry(i >> by).or_insert(0) += c;
+            }
+        }
+        self.scale -= by as i32;
+    }
+
+    /// Merge samples of another snapshot into this one.
+    ///
+    /// Both are downscaled to the lower scale first, and may be downscaled
+    /// further to fit bucket limit.
+    pub fn merge(&mut self, other: &ExponentialHistogramSnapshot) {
+        let mut other = other.clone();
+        if other.scale > self.scale {
+            other.downscale((other.scale - self.scale) as u32);
+        } else {
+            self.downscale((self.scale - other.scale) as u32);
+        }
+
+        self.zero_threshold = self.zero_threshold.max(other.zero_threshold);
+        self.max_buckets = self.max_buckets.min(other.max_buckets);
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.sum += other.sum;
+        for (i, c) in other.positive {
+            *self.positive.entry(i).or_insert(0) += c;
+        }
+        for (i, c) in other.negative {
+            *self.negative.entry(i).or_insert(0) += c;
+        }
+
+        self.fit_max_buckets();
+    }
+
+    /// Subtract counts of an earlier snapshot of the same histogram.
+    ///
+    /// If counts are lower than `earlier`, the histogram is considered reset
+    /// and this snapshot is returned as is.
+    pub fn delta(&self, earlier: &ExponentialHistogramSnapshot) -> ExponentialHistogramSnapshot {
+        let mut earlier = earlier.clone();
+        if earlier.scale > self.scale {
+            earlier.downscale((earlier.scale - self.scale) as u32);
+        }
+
+        let subtract = |current: &BTreeMap<i32, u64>, earlier: &BTreeMap<i32, u64>| {
+            let mut delta = current.clone();
+            for (i, c) in earlier {
+                match delta.get_mut(i) {
+                    Some(v) if *v >= *c => *v -= c,
+                    _ => return None,
+                }
+            }
+            delta.retain(|_, c| *c > 0);
+            Some(delta)
+        };
+
+        match (
+            subtract(&self.positive, &earlier.positive),
+            subtract(&self.negative, &earlier.negative),
+        ) {
+            (Some(positive), Some(negative))
+                if earlier.scale == self.scale
+                    && self.count >= earlier.count
+                    && self.zero_count >= earlier.zero_count =>
+            {
+                ExponentialHistogramSnapshot {
+                    positive,
+                    negative,
+                    zero_count: self.zero_count - earlier.zero_count,
+                    count: self.count - earlier.count,
+                    sum: self.sum - earlier.sum,
+                    ..self.clone()
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Number of buckets from the lowest to the highest index.
+fn span_len(buckets: &BTreeMap<i32, u64>) -> usize {
+    match (buckets.keys().next(), buckets.keys().next_back()) {
+        (Some(low), Some(high)) => (high - low) as usize + 1,
+        _ => 0,
+    }
+}
+
+fn bucket_lower_bound(index: i32, scale: i32) -> f64 {
+    2f64.powf(index as f64 * 2f64.powi(-scale))
+}
+
+/// Index of the bucket of a positive value, following the OpenTelemetry
+/// mapping functions so exact powers of two land on bucket boundaries.
+fn bucket_index(value: f64, scale: i32) -> i32 {
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i32 - 1023;
+    let power_of_two = bits & ((1u64 << 52) - 1) == 0;
+
+    if scale <= 0 {
+        let exponent = if power_of_two { exponent - 1 } else { exponent };
+        exponent >> -scale
+    } else if power_of_two {
+        (exponent << scale) - 1
+    } else {
+        let scale_factor = std::f64::consts::LOG2_E * 2f64.powi(scale);
+        (value.ln() * scale_factor).ceil() as i32 - 1
+    }
+}
+
+#[cfg(feature = "ser")]
+impl Serialize for ExponentialHistogram {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let snapshot = self.snapshot();
+        let mut map = serializer.serialize_map(Some(5))?;
+
+        map.serialize_entry("scale", &snapshot.scale())?;
+        map.serialize_entry("count", &snapshot.count())?;
+        map.serialize_entry("sum", &snapshot.sum())?;
+        map.serialize_entry("zero_count", &snapshot.zero_count())?;
+        map.serialize_entry("positive", &snapshot.positive)?;
+        map.serialize_entry("negative", &snapshot.negative)?;
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bucket_index, ExponentialHistogram, MIN_SCALE};
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(-1, bucket_index(1.0, 0));
+        assert_eq!(0, bucket_index(1.5, 0));
+        assert_eq!(0, bucket_index(2.0, 0));
+        assert_eq!(1, bucket_index(3.0, 0));
+        assert_eq!(3, bucket_index(4.0, 1));
+        assert_eq!(4, bucket_index(5.0, 1));
+        assert_eq!(0, bucket_index(10.0, -2));
+        assert_eq!(1, bucket_index(17.0, -2));
+        assert_eq!(-1, bucket_index(0.75, 0));
+    }
+
+    #[test]
+    fn test_exponential_histogram() {
+        let histogram = ExponentialHistogram::new(2);
+
+        histogram.update(0.0);
+        histogram.update(1.0);
+        histogram.update(3.0);
+        histogram.update(-3.0);
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(4, snapshot.count());
+        assert_eq!(1, snapshot.zero_count());
+        assert_eq!(1.0, snapshot.sum());
+        assert_eq!(2, snapshot.positive_buckets().count());
+        assert_eq!(1, snapshot.negative_buckets().count());
+
+        for (i, _) in snapshot.positive_buckets() {
+            let (low, high) = snapshot.bucket_bounds(i);
+            assert!((low < 1.0 && 1.0 <= high) || (low < 3.0 && 3.0 <= high));
+        }
+
+        let p99 = snapshot.quantile(0.99);
+        assert!(p99 > 2.5 && p99 < 3.5);
+        let p10 = snapshot.quantile(0.1);
+        assert!(p10 < -2.5 && p10 > -3.5);
+
+        histogram.update(f64::INFINITY);
+        histogram.update(f64::NEG_INFINITY);
+        histogram.update(f64::NAN);
+        let snapshot = histogram.snapshot();
+        assert_eq!(4, snapshot.count());
+        assert_eq!(1.0, snapshot.sum());
+        assert_eq!(2, snapshot.scale());
+    }
+
+    #[test]
+    fn test_downscale_to_fit() {
+        let histogram = ExponentialHistogram::new(8);
+
+        histogram.update(1.0);
+        histogram.update(1_000_000.0);
+
+        let mut snapshot = histogram.snapshot();
+        assert!(snapshot.scale() < 8);
+        assert_eq!(2, snapshot.positive_buckets().map(|(_, c)| c).sum::<u64>());
+
+        snapshot.downscale(64);
+        assert_eq!(MIN_SCALE, snapshot.scale());
+        assert_eq!(2, snapshot.positive_buckets().map(|(_, c)| c).sum::<u64>());
+    }
+
+    #[test]
+    fn test_merge_and_delta() {
+        let h1 = ExponentialHistogram::new(4);
+        let h2 = ExponentialHistogram::new(1);
+
+        h1.update(10.0);
+        h2.update(10.0);
+        h2.update(100.0);
+
+        let mut merged = h1.snapshot();
+        merged.merge(&h2.snapshot());
+        assert_eq!(1, merged.scale());
+        assert_eq!(3, merged.count());
+        assert_eq!(120.0, merged.sum());
+        assert_eq!(
+            vec![2, 1],
+            merged
+                .positive_buckets()
+                .map(|(_, c)| c)
+                .collect::<Vec<u64>>()
+        );
+
+        let earlier = h2.snapshot();
+        h2.update(100.0);
+        let delta = h2.snapshot().delta(&earlier);
+        assert_eq!(1, delta.count());
+        assert_eq!(100.0, delta.sum());
+        assert_eq!(1, delta.positive_buckets().count());
+    }
+}
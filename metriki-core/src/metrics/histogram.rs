@@ -1,5 +1,10 @@
-use std::sync::{Arc, RwLock};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
 
+use crossbeam_utils::CachePadded;
+#[cfg(feature = "codec")]
+use hdrhistogram::serialization::{Deserializer, Serializer as _, V2DeflateSerializer};
 use hdrhistogram::Histogram as HdrHistogram;
 
 #[cfg(feature = "ser")]
@@ -7,43 +12,115 @@ use serde::ser::SerializeMap;
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
-const DEFAULT_RANGE_MAX: u64 = 3600 * 24;
+use super::exemplar::{Exemplar, ExemplarReservoir};
+use crate::utils;
+
+pub(crate) const DEFAULT_RANGE_MAX: u64 = 3600 * 24;
 
 /// Histograms are used to record the distribution of data over time.
 ///
 /// By default, `Histogram` uses HdrHistogram for better data accuracy
 /// and smaller memory footprint.
+///
+/// A histogram can be striped into several HdrHistograms, so threads
+/// recording samples at the same time won't contend on a single lock.
+/// Stripes are merged when a snapshot is taken.
 #[derive(Debug)]
 pub struct Histogram {
-    inner: Arc<RwLock<HdrHistogram<u64>>>,
+    inner: Recorder,
+    exemplars: Mutex<ExemplarReservoir>,
+}
+
+#[derive(Debug)]
+enum Recorder {
+    Single(RwLock<HdrHistogram<u64>>),
+    Striped(Vec<CachePadded<Mutex<HdrHistogram<u64>>>>),
+}
+
+fn new_hdr_histogram() -> HdrHistogram<u64> {
+    HdrHistogram::<u64>::new_with_bounds(1, DEFAULT_RANGE_MAX, 2).unwrap()
 }
 
 #[derive(Debug)]
 pub struct HistogramSnapshot {
     inner: HdrHistogram<u64>,
+    exemplars: Vec<Exemplar>,
 }
 
 impl Histogram {
     pub(crate) fn new() -> Histogram {
-        let inner = HdrHistogram::<u64>::new_with_bounds(1, DEFAULT_RANGE_MAX, 2).unwrap();
+        Histogram::with_stripes(1)
+    }
+
+    /// Create a histogram that records samples into given number of stripes.
+    pub(crate) fn with_stripes(stripes: usize) -> Histogram {
+        let inner = if stripes > 1 {
+            Recorder::Striped(
+                (0..stripes)
+                    .map(|_| CachePadded::new(Mutex::new(new_hdr_histogram())))
+                    .collect(),
+            )
+        } else {
+            Recorder::Single(RwLock::new(new_hdr_histogram()))
+        };
 
         Histogram {
-            inner: Arc::new(RwLock::new(inner)),
+            inner,
+            exemplars: Mutex::new(ExemplarReservoir::default()),
         }
     }
 
     pub fn update(&self, value: u64) {
-        let mut inner = self.inner.write().unwrap();
         let value = value.min(DEFAULT_RANGE_MAX);
         // ignore the error
-        inner.record(value).ok();
+        match self.inner {
+            Recorder::Single(ref inner) => {
+                inner.write().unwrap().record(value).ok();
+            }
+            Recorder::Striped(ref stripes) => {
+                let idx = utils::thread_stripe() % stripes.len();
+                stripes[idx].lock().unwrap().record(value).ok();
+            }
+        }
+    }
+
+    /// Record a sample value with an exemplar that links it to a trace.
+    ///
+    /// Only the latest exemplar of each power of two range of values is
+    /// kept. Exemplars are not reset by snapshots, so every reporter sees them.
+    ///
+    /// OpenMetrics doesn't allow exemplars on summaries, which histograms are
+    /// exported as by the Prometheus exporter, use `BucketedHistogram` to
+    /// scrape exemplars.
+    pub fn update_with_exemplar(&self, value: u64, exemplar: Exemplar) {
+        self.update(value);
+        self.exemplars
+            .lock()
+            .unwrap()
+            .record(value.min(DEFAULT_RANGE_MAX), exemplar);
     }
 
     pub fn snapshot(&self) -> HistogramSnapshot {
-        let mut hist = HdrHistogram::<u64>::new_with_bounds(1, DEFAULT_RANGE_MAX, 2).unwrap();
-        let mut inner = self.inner.write().unwrap();
-        std::mem::swap(&mut hist, &mut *inner);
-        HistogramSnapshot { inner: hist }
+        let mut hist = new_hdr_histogram();
+        match self.inner {
+            Recorder::Single(ref inner) => {
+                let mut inner = inner.write().unwrap();
+                std::mem::swap(&mut hist, &mut *inner);
+            }
+            Recorder::Striped(ref stripes) => {
+                for stripe in stripes.iter() {
+                    let mut stripe_hist = new_hdr_histogram();
+                    std::mem::swap(&mut stripe_hist, &mut *stripe.lock().unwrap());
+                    // stripes share the same bounds so this won't fail
+                    hist.add(&stripe_hist).ok();
+                }
+            }
+        }
+        let exemplars = self.exemplars.lock().unwrap().exemplars();
+        HistogramSnapshot {
+            inner: hist,
+            exemplars,
+        }
     }
 }
 
@@ -71,8 +148,141 @@ impl HistogramSnapshot {
     pub fn quantile(&self, quantile: f64) -> u64 {
         self.inner.value_at_quantile(quantile)
     }
+
+    /// Sum of all recorded samples.
+    ///
+    /// Like `mean`, the sum is calculated from recorded buckets, so it's
+    /// accurate up to the precision of the histogram.
+    pub fn sum(&self) -> u64 {
+        self.inner
+            .iter_recorded()
+            .map(|v| {
+                self.inner
+                    .median_equivalent(v.value_iterated_to())
+                    .saturating_mul(v.count_at_value())
+            })
+            .fold(0u64, |acc, v| acc.saturating_add(v))
+    }
+
+    /// Percentage of samples that are equal to or less than given value.
+    ///
+    /// The result is in range of `[0, 100]`.
+    pub fn percentile_rank(&self, value: u64) -> f64 {
+        self.inner.percentile_below(value)
+    }
+
+    /// Number of samples recorded between `low` and `high`, both inclusive.
+    pub fn count_between(&self, low: u64, high: u64) -> u64 {
+        self.inner.count_between(low, high)
+    }
+
+    /// Merge samples of another snapshot into this one.
+    ///
+    /// This is useful to aggregate histograms from threads, shards or
+    /// multiple processes. Exemplars of both snapshots are kept.
+    ///
+    /// Returns an error, leaving this snapshot unchanged, if samples of the
+    /// other one can't be added, like when its range can't be covered.
+    pub fn merge(&mut self, other: &HistogramSnapshot) -> Result<(), MergeError> {
+        // allow the range to grow for snapshots decoded from elsewhere
+        self.inner.auto(true);
+        self.inner
+            .add(&other.inner)
+            .map_err(|e| MergeError(format!("{:?}", e)))?;
+
+        let mut reservoir = ExemplarReservoir::default();
+        for exemplar in self
+            .exemplars
+            .drain(..)
+            .chain(other.exemplars.iter().cloned())
+        {
+            reservoir.offer(exemplar);
+        }
+        self.exemplars = reservoir.take();
+        Ok(())
+    }
+
+    /// Iterate over recorded buckets, as `(value, count)` pairs.
+    ///
+    /// `value` is the highest value that is equivalent to samples in this
+    /// bucket, and `count` is the number of samples recorded in it.
+    pub fn iter_recorded(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.inner
+            .iter_recorded()
+            .map(|v| (v.value_iterated_to(), v.count_at_value()))
+    }
+
+    /// Encode the snapshot with HdrHistogram's V2 compressed format.
+    ///
+    /// The encoded data is lossless and compatible with other HdrHistogram
+    /// implementations. Exemplars are not included.
+    #[cfg(feature = "codec")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "codec")))]
+    pub fn encode(&self) -> Result<Vec<u8>, HistogramCodecError> {
+        let mut buf = Vec::new();
+        V2DeflateSerializer::new()
+            .serialize(&self.inner, &mut buf)
+            .map_err(|e| HistogramCodecError(e.to_string()))?;
+        Ok(buf)
+    }
+
+    /// Decode a snapshot from data in HdrHistogram's V2 format, compressed or not.
+    #[cfg(feature = "codec")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "codec")))]
+    pub fn decode(data: &[u8]) -> Result<HistogramSnapshot, HistogramCodecError> {
+        let mut reader = data;
+        let inner = Deserializer::new()
+            .deserialize(&mut reader)
+            .map_err(|e| HistogramCodecError(e.to_string()))?;
+        Ok(HistogramSnapshot {
+            inner,
+            exemplars: Vec::new(),
+        })
+    }
+
+    /// Latest exemplars of the histogram, ordered by their values.
+    pub fn exemplars(&self) -> &[Exemplar] {
+        self.exemplars.as_slice()
+    }
+
+    /// Returns the exemplar closest to the value at given quantile.
+    pub fn exemplar_at_quantile(&self, quantile: f64) -> Option<&Exemplar> {
+        let value = self.quantile(quantile);
+        self.exemplars.iter().min_by(|a, b| {
+            let distance = |e: &Exemplar| (e.value() - value as f64).abs();
+            distance(a).total_cmp(&distance(b))
+        })
+    }
+}
+
+/// Error on encoding or decoding `HistogramSnapshot`.
+#[cfg(feature = "codec")]
+#[cfg_attr(docsrs, doc(cfg(feature = "codec")))]
+#[derive(Debug)]
+pub struct HistogramCodecError(String);
+
+#[cfg(feature = "codec")]
+impl fmt::Display for HistogramCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to encode or decode histogram: {}", self.0)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl Error for HistogramCodecError {}
+
+/// Error returned by `HistogramSnapshot::merge`.
+#[derive(Debug)]
+pub struct MergeError(String);
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to merge histogram snapshot: {}", self.0)
+    }
 }
 
+impl Error for MergeError {}
+
 #[cfg(feature = "ser")]
 impl Serialize for Histogram {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -99,7 +309,10 @@ impl Serialize for Histogram {
 
 #[cfg(test)]
 mod test {
-    use super::{Histogram, DEFAULT_RANGE_MAX};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{Exemplar, Histogram, DEFAULT_RANGE_MAX};
 
     #[test]
     fn test_histogram_range() {
@@ -134,5 +347,125 @@ mod test {
         assert_eq!(0, snapshot.count());
         assert_eq!(0, snapshot.min());
         assert_eq!(0, snapshot.quantile(0.9));
+        assert!(snapshot.exemplar_at_quantile(0.9).is_none());
+    }
+
+    #[test]
+    fn test_histogram_exemplars() {
+        let histogram = Histogram::new();
+
+        for i in 1..=100 {
+            histogram.update(i);
+        }
+        histogram.update_with_exemplar(5, Exemplar::new("trace-low", vec![]));
+        histogram.update_with_exemplar(99, Exemplar::new("trace-high", vec![]));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(2, snapshot.exemplars().len());
+        assert_eq!(
+            "trace-high",
+            snapshot.exemplar_at_quantile(0.99).unwrap().trace_id()
+        );
+        assert_eq!(
+            "trace-low",
+            snapshot.exemplar_at_quantile(0.01).unwrap().trace_id()
+        );
+
+        // kept for later snapshots
+        assert_eq!(2, histogram.snapshot().exemplars().len());
+    }
+
+    #[test]
+    fn test_histogram_statistics() {
+        let histogram = Histogram::new();
+
+        for i in 1..=100 {
+            histogram.update(i);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(5050, snapshot.sum());
+        assert_eq!(50.0, snapshot.percentile_rank(50));
+        assert_eq!(100.0, snapshot.percentile_rank(1000));
+        assert_eq!(11, snapshot.count_between(10, 20));
+    }
+
+    #[test]
+    fn test_striped_histogram() {
+        let histogram = Arc::new(Histogram::with_stripes(4));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let histogram = histogram.clone();
+                thread::spawn(move || {
+                    for i in 1..=100 {
+                        histogram.update(i);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(800, snapshot.count());
+        assert_eq!(1, snapshot.min());
+        assert_eq!(100, snapshot.max());
+
+        assert_eq!(0, histogram.snapshot().count());
+    }
+
+    #[test]
+    fn test_merge_histogram_snapshots() {
+        let h1 = Histogram::new();
+        let h2 = Histogram::new();
+
+        for i in 1..=50 {
+            h1.update(i);
+        }
+        for i in 51..100 {
+            h2.update(i);
+        }
+        h2.update_with_exemplar(100, Exemplar::new("trace", vec![]));
+
+        let mut snapshot = h1.snapshot();
+        snapshot.merge(&h2.snapshot()).unwrap();
+
+        assert_eq!(100, snapshot.count());
+        assert_eq!(1, snapshot.min());
+        assert_eq!(100, snapshot.max());
+        assert_eq!(1, snapshot.exemplars().len());
+        assert_eq!(
+            100,
+            snapshot
+                .iter_recorded()
+                .map(|(_, count)| count)
+                .sum::<u64>()
+        );
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn test_encode_decode_histogram_snapshot() {
+        use super::HistogramSnapshot;
+
+        let histogram = Histogram::new();
+        for i in 1..=1000 {
+            histogram.update(i);
+        }
+        let snapshot = histogram.snapshot();
+
+        let bytes = snapshot.encode().unwrap();
+        let decoded = HistogramSnapshot::decode(&bytes).unwrap();
+
+        assert_eq!(snapshot.count(), decoded.count());
+        assert_eq!(snapshot.quantile(0.99), decoded.quantile(0.99));
+        assert_eq!(
+            snapshot.iter_recorded().collect::<Vec<(u64, u64)>>(),
+            decoded.iter_recorded().collect::<Vec<(u64, u64)>>()
+        );
+
+        assert!(HistogramSnapshot::decode(b"invalid").is_err());
     }
 }
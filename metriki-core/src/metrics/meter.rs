@@ -1,4 +1,3 @@
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant, SystemTime};
 
 use crossbeam_utils::atomic::AtomicCell;
@@ -8,21 +7,27 @@ use serde::ser::SerializeMap;
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
+use super::adder::LongAdder;
 use crate::utils;
 
 /// Meters are used to calculate rate of an event.
 #[derive(Debug)]
 pub struct Meter {
     moving_averages: ExponentiallyWeightedMovingAverages,
-    count: AtomicU64,
+    count: LongAdder,
     start_time: SystemTime,
 }
 
 impl Meter {
     pub(crate) fn new() -> Meter {
+        Meter::with_stripes(1)
+    }
+
+    /// Create a meter that spreads marks over given number of stripes.
+    pub(crate) fn with_stripes(stripes: usize) -> Meter {
         Meter {
-            moving_averages: ExponentiallyWeightedMovingAverages::new(),
-            count: AtomicU64::from(0),
+            moving_averages: ExponentiallyWeightedMovingAverages::new(stripes),
+            count: LongAdder::new(stripes),
             start_time: SystemTime::now(),
         }
     }
@@ -32,7 +37,7 @@ impl Meter {
     }
 
     pub fn mark_n(&self, n: u64) {
-        self.count.fetch_add(n, Ordering::Relaxed);
+        self.count.add(n);
         self.moving_averages.tick_if_needed();
         self.moving_averages.update(n);
     }
@@ -53,7 +58,7 @@ impl Meter {
     }
 
     pub fn count(&self) -> u64 {
-        self.count.load(Ordering::Relaxed)
+        self.count.sum()
     }
 
     pub fn mean_rate(&self) -> f64 {
@@ -78,7 +83,6 @@ struct ExponentiallyWeightedMovingAverage {
     alpha: f64,
     interval_nanos: u64,
 
-    uncounted: AtomicCell<u64>,
     rate: AtomicCell<Option<f64>>,
 }
 
@@ -88,17 +92,11 @@ impl ExponentiallyWeightedMovingAverage {
             alpha,
             interval_nanos: utils::secs_to_nanos(interval_secs),
 
-            uncounted: AtomicCell::new(0),
             rate: AtomicCell::new(None),
         }
     }
 
-    fn update(&self, n: u64) {
-        self.uncounted.fetch_add(n);
-    }
-
-    fn tick(&self) {
-        let count = self.uncounted.swap(0);
+    fn tick(&self, count: u64) {
         let instant_rate = count as f64 / self.interval_nanos as f64;
 
         if let Some(prev_rate) = self.rate.load() {
@@ -124,6 +122,9 @@ struct ExponentiallyWeightedMovingAverages {
     m5: ExponentiallyWeightedMovingAverage,
     m15: ExponentiallyWeightedMovingAverage,
 
+    // events not counted into moving averages since last tick, shared by
+    // all averages
+    uncounted: LongAdder,
     last_tick: AtomicCell<Instant>,
 }
 
@@ -136,7 +137,7 @@ const DEFAULT_INTERVAL_SECS: u64 = 5;
 const DEFAULT_INTERVAL_MILLIS: u64 = DEFAULT_INTERVAL_SECS * 1000;
 
 impl ExponentiallyWeightedMovingAverages {
-    fn new() -> ExponentiallyWeightedMovingAverages {
+    fn new(stripes: usize) -> ExponentiallyWeightedMovingAverages {
         ExponentiallyWeightedMovingAverages {
             m1: ExponentiallyWeightedMovingAverage::new(
                 alpha(DEFAULT_INTERVAL_SECS, 1),
@@ -153,14 +154,13 @@ impl ExponentiallyWeightedMovingAverages {
                 DEFAULT_INTERVAL_SECS,
             ),
 
+            uncounted: LongAdder::new(stripes),
             last_tick: AtomicCell::new(Instant::now()),
         }
     }
 
     fn update(&self, n: u64) {
-        self.m1.update(n);
-        self.m5.update(n);
-        self.m15.update(n);
+        self.uncounted.add(n);
     }
 
     fn tick_if_needed(&self) {
@@ -181,9 +181,10 @@ impl ExponentiallyWeightedMovingAverages {
             {
                 let required_ticks = tick_age / DEFAULT_INTERVAL_MILLIS;
                 for _ in 0..required_ticks {
-                    self.m1.tick();
-                    self.m5.tick();
-                    self.m15.tick();
+                    let count = self.uncounted.take();
+                    self.m1.tick(count);
+                    self.m5.tick(count);
+                    self.m15.tick(count);
                 }
             }
         }
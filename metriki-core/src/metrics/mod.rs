@@ -1,13 +1,21 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
+mod adder;
+mod apdex;
+mod bucketed;
 mod counter;
+mod exemplar;
+mod exponential;
 mod gauge;
 mod histogram;
 mod meter;
+mod monotonic;
 mod timer;
+mod unique;
 
 #[derive(Clone, Debug)]
 pub enum Metric {
@@ -16,6 +24,11 @@ pub enum Metric {
     Gauge(Arc<Gauge>),
     Histogram(Arc<Histogram>),
     Counter(Arc<Counter>),
+    Apdex(Arc<Apdex>),
+    MonotonicCounter(Arc<MonotonicCounter>),
+    BucketedHistogram(Arc<BucketedHistogram>),
+    ExponentialHistogram(Arc<ExponentialHistogram>),
+    UniqueCounter(Arc<UniqueCounter>),
 }
 
 impl Metric {
@@ -44,6 +57,31 @@ impl Metric {
         Counter::new().into()
     }
 
+    /// Create bucketed histogram with given buckets
+    pub fn bucketed_histogram(buckets: Buckets) -> Arc<BucketedHistogram> {
+        BucketedHistogram::new(buckets).into()
+    }
+
+    /// Create exponential histogram with given initial scale
+    pub fn exponential_histogram(scale: i32) -> Arc<ExponentialHistogram> {
+        ExponentialHistogram::new(scale).into()
+    }
+
+    /// Create apdex with given threshold
+    pub fn apdex(threshold: Duration) -> Arc<Apdex> {
+        Apdex::new(threshold).into()
+    }
+
+    /// Create default monotonic counter
+    pub fn monotonic_counter() -> Arc<MonotonicCounter> {
+        MonotonicCounter::new().into()
+    }
+
+    /// Create unique counter with given precision and optional window
+    pub fn unique_counter(precision: u8, window: Option<Duration>) -> Arc<UniqueCounter> {
+        UniqueCounter::new(precision, window).into()
+    }
+
     /// Convert the Metric to `Meter`
     pub fn as_meter(&self) -> Option<Arc<Meter>> {
         match self {
@@ -83,6 +121,46 @@ impl Metric {
             _ => None,
         }
     }
+
+    /// Convert the Metric to `BucketedHistogram`
+    pub fn as_bucketed_histogram(&self) -> Option<Arc<BucketedHistogram>> {
+        match self {
+            Metric::BucketedHistogram(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+
+    /// Convert the Metric to `ExponentialHistogram`
+    pub fn as_exponential_histogram(&self) -> Option<Arc<ExponentialHistogram>> {
+        match self {
+            Metric::ExponentialHistogram(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+
+    /// Convert the Metric to `Apdex`
+    pub fn as_apdex(&self) -> Option<Arc<Apdex>> {
+        match self {
+            Metric::Apdex(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+
+    /// Convert the Metric to `UniqueCounter`
+    pub fn as_unique_counter(&self) -> Option<Arc<UniqueCounter>> {
+        match self {
+            Metric::UniqueCounter(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
+
+    /// Convert the Metric to `MonotonicCounter`
+    pub fn as_monotonic_counter(&self) -> Option<Arc<MonotonicCounter>> {
+        match self {
+            Metric::MonotonicCounter(m) => Some(m.clone()),
+            _ => None,
+        }
+    }
 }
 
 impl From<Arc<Meter>> for Metric {
@@ -103,6 +181,36 @@ impl From<Arc<Counter>> for Metric {
     }
 }
 
+impl From<Arc<BucketedHistogram>> for Metric {
+    fn from(f: Arc<BucketedHistogram>) -> Metric {
+        Metric::BucketedHistogram(f)
+    }
+}
+
+impl From<Arc<ExponentialHistogram>> for Metric {
+    fn from(f: Arc<ExponentialHistogram>) -> Metric {
+        Metric::ExponentialHistogram(f)
+    }
+}
+
+impl From<Arc<Apdex>> for Metric {
+    fn from(f: Arc<Apdex>) -> Metric {
+        Metric::Apdex(f)
+    }
+}
+
+impl From<Arc<MonotonicCounter>> for Metric {
+    fn from(f: Arc<MonotonicCounter>) -> Metric {
+        Metric::MonotonicCounter(f)
+    }
+}
+
+impl From<Arc<UniqueCounter>> for Metric {
+    fn from(f: Arc<UniqueCounter>) -> Metric {
+        Metric::UniqueCounter(f)
+    }
+}
+
 impl From<Arc<Gauge>> for Metric {
     fn from(f: Arc<Gauge>) -> Metric {
         Metric::Gauge(f)
@@ -127,12 +235,30 @@ impl Serialize for Metric {
             Metric::Gauge(inner) => inner.serialize(serializer),
             Metric::Histogram(inner) => inner.serialize(serializer),
             Metric::Counter(inner) => inner.serialize(serializer),
+            Metric::Apdex(inner) => inner.serialize(serializer),
+            Metric::MonotonicCounter(inner) => inner.serialize(serializer),
+            Metric::BucketedHistogram(inner) => inner.serialize(serializer),
+            Metric::ExponentialHistogram(inner) => inner.serialize(serializer),
+            Metric::UniqueCounter(inner) => inner.serialize(serializer),
         }
     }
 }
 
+pub use apdex::{Apdex, ApdexSnapshot};
+pub use bucketed::{BucketedHistogram, BucketedHistogramSnapshot, Buckets};
 pub use counter::Counter;
+pub use exemplar::Exemplar;
+pub use exponential::{
+    ExponentialHistogram, ExponentialHistogramSnapshot, MAX_SCALE as MAX_EXPONENTIAL_SCALE,
+    MIN_SCALE as MIN_EXPONENTIAL_SCALE,
+};
 pub use gauge::{CachedGauge, Gauge, GaugeFn, StaticGauge};
-pub use histogram::{Histogram, HistogramSnapshot};
+#[cfg(feature = "codec")]
+pub use histogram::HistogramCodecError;
+pub use histogram::{Histogram, HistogramSnapshot, MergeError};
 pub use meter::Meter;
-pub use timer::{Timer, TimerContext, TimerContextArc};
+pub use monotonic::MonotonicCounter;
+pub use timer::{Instrumented, Outcome, Timer, TimerContext, TimerContextArc};
+pub use unique::{
+    UniqueCounter, MAX_PRECISION as MAX_UNIQUE_PRECISION, MIN_PRECISION as MIN_UNIQUE_PRECISION,
+};
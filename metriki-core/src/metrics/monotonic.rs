@@ -0,0 +1,49 @@
+#[cfg(feature = "ser")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "ser")]
+use serde::{Serialize, Serializer};
+
+use super::adder::LongAdder;
+
+/// Monotonic counters are integer totals that only go up.
+///
+/// Unlike `Counter`, which can be decremented and is reported as a gauge,
+/// monotonic counters are reported as counters by reporters that distinguish
+/// the two, so functions like Prometheus `rate()` work on them.
+#[derive(Debug)]
+pub struct MonotonicCounter {
+    value: LongAdder,
+}
+
+impl MonotonicCounter {
+    pub(crate) fn new() -> MonotonicCounter {
+        MonotonicCounter::with_stripes(1)
+    }
+
+    /// Create a counter that spreads increments over given number of stripes.
+    pub(crate) fn with_stripes(stripes: usize) -> MonotonicCounter {
+        MonotonicCounter {
+            value: LongAdder::new(stripes),
+        }
+    }
+
+    pub fn inc(&self, n: u64) {
+        self.value.add(n);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.value.sum()
+    }
+}
+
+#[cfg(feature = "ser")]
+impl Serialize for MonotonicCounter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("value", &self.value())?;
+        map.end()
+    }
+}
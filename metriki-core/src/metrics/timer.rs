@@ -1,38 +1,73 @@
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Instant;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use pin_project_lite::pin_project;
 
 #[cfg(feature = "ser")]
 use serde::ser::SerializeMap;
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
-use super::{Histogram, HistogramSnapshot, Meter};
+use super::{Apdex, Exemplar, Histogram, HistogramSnapshot, Meter};
+use crate::family::TimerFamily;
 
 /// Timers are combination of `Histogram` and `Meter`.
 ///
 /// Timers are handy for tracking rate and latency of a special part of code.
+/// Optionally, a timer can also track `Apdex` of its latency, see
+/// `Timer::enable_apdex`.
 #[derive(Debug)]
 pub struct Timer {
     rate: Meter,
     latency: Histogram,
+    apdex: OnceCell<Apdex>,
 }
 
+/// TimerContext records its timing once, when it's stopped or dropped.
 #[derive(Debug)]
 pub struct TimerContext<'a> {
     start_at: Instant,
     timer: &'a Timer,
+    stopped: bool,
+}
+
+/// Result of the timed operation, used to track latency of success, failure
+/// and cancellation separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    Ok,
+    Err,
+    Cancelled,
+}
+
+impl Outcome {
+    /// Label value of the outcome: `ok`, `err` or `cancelled`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Err => "err",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
 }
 
 /// TimerContext holds a `Arc` reference of `Timer`.
 /// This API is designed for using in async scenario where context are passed
 /// across threads.
 ///
-/// Note that this timer context requires explict `stop` to record its
-/// latency. The implicit drop doesn't apply to it.
+/// The timing is recorded exactly once, when the context is stopped or
+/// dropped. A context dropped without `stop`, like in a cancelled future, is
+/// recorded with `Outcome::Cancelled`.
 #[derive(Debug)]
 pub struct TimerContextArc {
     start_at: Instant,
     timer: Arc<Timer>,
+    outcomes: Option<Arc<TimerFamily>>,
+    stopped: bool,
 }
 
 impl TimerContextArc {
@@ -45,51 +80,208 @@ impl TimerContextArc {
     /// The returned `TimerContext` can be stopped or dropped to record its timing.
     pub fn start_at(timer: Arc<Timer>, start_at: Instant) -> TimerContextArc {
         timer.rate.mark();
-        TimerContextArc { start_at, timer }
+        TimerContextArc {
+            start_at,
+            timer,
+            outcomes: None,
+            stopped: false,
+        }
+    }
+
+    /// Also record the timing by outcome, into timers of the family.
+    ///
+    /// The family is expected to have a single label key, like `outcome`,
+    /// whose value is set by `Outcome::as_str`.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use metriki_core::metrics::{Outcome, TimerContextArc};
+    /// use metriki_core::MetricsRegistry;
+    ///
+    /// let registry = MetricsRegistry::new();
+    /// let outcomes = Arc::new(registry.timer_family("query.outcome", &["outcome"]));
+    ///
+    /// let ctx = TimerContextArc::start(registry.timer("query")).with_outcomes(outcomes);
+    /// ctx.stop_with_outcome(Outcome::Ok);
+    /// ```
+    pub fn with_outcomes(mut self, outcomes: Arc<TimerFamily>) -> TimerContextArc {
+        self.outcomes = Some(outcomes);
+        self
     }
 
     /// Stop the timer context.
-    pub fn stop(&self) {
+    pub fn stop(mut self) {
+        self.finish(None, None);
+    }
+
+    /// Stop the timer context and attach an exemplar to its latency sample.
+    pub fn stop_with_exemplar(mut self, exemplar: Exemplar) {
+        self.finish(None, Some(exemplar));
+    }
+
+    /// Stop the timer context with the outcome of timed operation.
+    pub fn stop_with_outcome(mut self, outcome: Outcome) {
+        self.finish(Some(outcome), None);
+    }
+
+    fn finish(&mut self, outcome: Option<Outcome>, exemplar: Option<Exemplar>) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+
         let elapsed = Instant::now() - self.start_at;
-        let elapsed_ms = elapsed.as_millis();
+        self.timer.record_duration(elapsed, outcome, exemplar);
+        if let (Some(outcomes), Some(outcome)) = (self.outcomes.as_ref(), outcome) {
+            outcomes.with(&[outcome.as_str()]).update(elapsed);
+        }
+    }
+}
+
+impl Drop for TimerContextArc {
+    fn drop(&mut self) {
+        self.finish(Some(Outcome::Cancelled), None);
+    }
+}
+
+pin_project! {
+    /// A future that records its execution time with a `Timer`.
+    ///
+    /// The timer starts when the future is created, and records when the
+    /// future completes. If it's dropped before completion, it's recorded
+    /// as cancelled.
+    ///
+    /// Create it with `Timer::time_future` or `Instrumented::new`.
+    #[derive(Debug)]
+    pub struct Instrumented<F> {
+        #[pin]
+        inner: F,
+        ctx: Option<TimerContextArc>,
+    }
+}
+
+impl<F> Instrumented<F> {
+    pub fn new(timer: Arc<Timer>, inner: F) -> Instrumented<F> {
+        Instrumented {
+            inner,
+            ctx: Some(TimerContextArc::start(timer)),
+        }
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = this.inner.poll(cx);
 
-        self.timer.latency.update(elapsed_ms as u64);
+        if output.is_ready() {
+            if let Some(ctx) = this.ctx.take() {
+                ctx.stop();
+            }
+        }
+
+        output
     }
 }
 
 impl Timer {
     pub(crate) fn new() -> Timer {
+        Timer::with_stripes(1, 1)
+    }
+
+    /// Create a timer with striped histogram and meter.
+    pub(crate) fn with_stripes(histogram_stripes: usize, meter_stripes: usize) -> Timer {
         Timer {
-            rate: Meter::new(),
-            latency: Histogram::new(),
+            rate: Meter::with_stripes(meter_stripes),
+            latency: Histogram::with_stripes(histogram_stripes),
+            apdex: OnceCell::new(),
         }
     }
 
+    /// Track `Apdex` of this timer with given threshold.
+    ///
+    /// Timings are classified by the threshold, and those stopped with
+    /// `Outcome::Err` are counted as frustrated. Once enabled, the threshold
+    /// can't be changed, and this function returns `false` on later calls.
+    pub fn enable_apdex(&self, threshold: Duration) -> bool {
+        self.apdex.set(Apdex::new(threshold)).is_ok()
+    }
+
+    /// Returns the apdex of timer, if enabled.
+    pub fn apdex(&self) -> Option<&Apdex> {
+        self.apdex.get()
+    }
+
     /// Start a timer context for recording.
     /// The returned `TimerContext` can be stopped or dropped to record its timing.
-    pub fn start(&self) -> TimerContext {
+    pub fn start(&self) -> TimerContext<'_> {
         self.start_at(Instant::now())
     }
 
     /// Start a timer context for recording that started at given time.
     /// The returned `TimerContext` can be stopped or dropped to record its timing.
-    pub fn start_at(&self, start_at: Instant) -> TimerContext {
+    pub fn start_at(&self, start_at: Instant) -> TimerContext<'_> {
         self.rate.mark();
         TimerContext {
             start_at,
             timer: self,
+            stopped: false,
         }
     }
 
     /// Execute closure and record its execution with this timer.
     pub fn scoped<F, R>(&self, f: F) -> R
     where
-        F: Fn() -> R,
+        F: FnOnce() -> R,
     {
-        let ctx = self.start();
-        let result = f();
-        ctx.stop();
-        result
+        let _ctx = self.start();
+        f()
+    }
+
+    /// Wrap the future to record its execution with this timer.
+    ///
+    /// ```
+    /// use metriki_core::MetricsRegistry;
+    ///
+    /// # async fn query() {}
+    /// # async fn example() {
+    /// let registry = MetricsRegistry::new();
+    /// let result = registry.timer("query").time_future(query()).await;
+    /// # }
+    /// ```
+    pub fn time_future<F: Future>(self: &Arc<Self>, future: F) -> Instrumented<F> {
+        Instrumented::new(self.clone(), future)
+    }
+
+    /// Record an event with given duration.
+    pub fn update(&self, duration: Duration) {
+        self.rate.mark();
+        self.record_duration(duration, None, None);
+    }
+
+    fn record_duration(
+        &self,
+        elapsed: Duration,
+        outcome: Option<Outcome>,
+        exemplar: Option<Exemplar>,
+    ) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+
+        if let Some(apdex) = self.apdex.get() {
+            if outcome == Some(Outcome::Err) {
+                apdex.mark_frustrated();
+            } else {
+                apdex.update(elapsed);
+            }
+        }
+
+        if let Some(exemplar) = exemplar {
+            self.latency.update_with_exemplar(elapsed_ms, exemplar);
+        } else {
+            self.latency.update(elapsed_ms);
+        }
     }
 
     /// Returns the rates of timer
@@ -104,17 +296,27 @@ impl Timer {
 }
 
 impl<'a> TimerContext<'a> {
-    pub fn stop(&self) {
-        let elapsed = Instant::now() - self.start_at;
-        let elapsed_ms = elapsed.as_millis();
+    pub fn stop(mut self) {
+        self.finish(None);
+    }
 
-        self.timer.latency.update(elapsed_ms as u64);
+    /// Stop the timer context and attach an exemplar to its latency sample.
+    pub fn stop_with_exemplar(mut self, exemplar: Exemplar) {
+        self.finish(Some(exemplar));
+    }
+
+    fn finish(&mut self, exemplar: Option<Exemplar>) {
+        if !self.stopped {
+            self.stopped = true;
+            self.timer
+                .record_duration(Instant::now() - self.start_at, None, exemplar);
+        }
     }
 }
 
 impl<'a> Drop for TimerContext<'a> {
     fn drop(&mut self) {
-        self.stop()
+        self.finish(None)
     }
 }
 
@@ -124,7 +326,8 @@ impl Serialize for Timer {
     where
         S: Serializer,
     {
-        let mut map = serializer.serialize_map(Some(13))?;
+        let apdex = self.apdex();
+        let mut map = serializer.serialize_map(Some(13 + apdex.map_or(0, |_| 1)))?;
 
         let rate = self.rate();
         let latency = self.latency();
@@ -145,15 +348,21 @@ impl Serialize for Timer {
         map.serialize_entry("p99", &latency.quantile(0.99))?;
         map.serialize_entry("p999", &latency.quantile(0.999))?;
 
+        if let Some(apdex) = apdex {
+            map.serialize_entry("apdex", &apdex.score())?;
+        }
+
         map.end()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
     use std::time::Duration;
 
-    use super::Timer;
+    use super::{Outcome, Timer, TimerContextArc};
+    use crate::registry::MetricsRegistry;
 
     #[test]
     fn test_drop_timer_context() {
@@ -170,6 +379,46 @@ mod test {
         }
 
         assert!(timer.rate().count() == 2);
+        assert_eq!(2, timer.latency().count());
+    }
+
+    #[test]
+    fn test_timer_context_arc_outcome() {
+        let registry = MetricsRegistry::new();
+        let timer = registry.timer("query");
+        let outcomes = Arc::new(registry.timer_family("query.outcome", &["outcome"]));
+
+        TimerContextArc::start(timer.clone())
+            .with_outcomes(outcomes.clone())
+            .stop_with_outcome(Outcome::Err);
+        {
+            let _dropped = TimerContextArc::start(timer.clone()).with_outcomes(outcomes.clone());
+        }
+        TimerContextArc::start(timer.clone()).stop();
+
+        assert_eq!(3, timer.latency().count());
+        assert_eq!(1, outcomes.with(&["err"]).latency().count());
+        assert_eq!(1, outcomes.with(&["cancelled"]).latency().count());
+        assert_eq!(0, outcomes.with(&["ok"]).latency().count());
+    }
+
+    #[test]
+    fn test_timer_apdex() {
+        let timer = Arc::new(Timer::new());
+        assert!(timer.apdex().is_none());
+
+        assert!(timer.enable_apdex(Duration::from_millis(100)));
+        assert!(!timer.enable_apdex(Duration::from_millis(200)));
+
+        timer.update(Duration::from_millis(10));
+        timer.update(Duration::from_millis(200));
+        TimerContextArc::start(timer.clone()).stop_with_outcome(Outcome::Err);
+
+        let apdex = timer.apdex().unwrap().snapshot();
+        assert_eq!(1, apdex.satisfied());
+        assert_eq!(1, apdex.tolerating());
+        assert_eq!(1, apdex.frustrated());
+        assert_eq!(0.5, apdex.score());
     }
 
     #[test]
@@ -180,5 +429,31 @@ mod test {
             std::thread::sleep(Duration::from_millis(10));
         });
         assert!(timer.rate().count() == 1);
+        assert_eq!(1, timer.latency().count());
+    }
+
+    #[test]
+    fn test_timer_update() {
+        let timer = Timer::new();
+
+        timer.update(Duration::from_millis(20));
+        timer.update(Duration::from_secs(1));
+
+        let latency = timer.latency();
+        assert_eq!(2, timer.rate().count());
+        assert_eq!(2, latency.count());
+        assert_eq!(20, latency.min());
+        assert!(latency.max() >= 1000);
+    }
+
+    #[test]
+    fn test_time_future() {
+        let timer = Arc::new(Timer::new());
+
+        let result = futures_executor::block_on(timer.time_future(async { 42 }));
+
+        assert_eq!(42, result);
+        assert_eq!(1, timer.rate().count());
+        assert_eq!(1, timer.latency().count());
     }
 }
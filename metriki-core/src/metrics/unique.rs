@@ -0,0 +1,264 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "ser")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "ser")]
+use serde::{Serialize, Serializer};
+use siphasher::sip::SipHasher13;
+
+/// Minimal precision of `UniqueCounter`, with 16 registers.
+pub const MIN_PRECISION: u8 = 4;
+/// Maximal precision of `UniqueCounter`, with 65536 registers.
+pub const MAX_PRECISION: u8 = 16;
+
+/// Unique counters estimate the number of distinct values observed, like
+/// unique users or IPs, without storing the values.
+///
+/// It's backed by HyperLogLog with `2^precision` registers of one byte each.
+/// The standard error of the estimate is about `1.04 / sqrt(2^precision)`,
+/// for example 1.6% with precision of 12.
+///
+/// With a window, values are observed into two sets of registers in turn,
+/// one for the current window and one for the last completed window, and
+/// the value is the estimate of the last completed window. So reporters of
+/// any interval read a whole window, which is 0 until the first one ends.
+/// Unique counters are reported as gauges.
+///
+/// Values are hashed with SipHash-1-3 of fixed keys, so counters built with
+/// different Rust releases or processes can be merged.
+#[derive(Debug)]
+pub struct UniqueCounter {
+    precision: u8,
+    registers: [Box<[AtomicU8]>; 2],
+    current: AtomicUsize,
+    window: Option<Duration>,
+    created_at: Instant,
+    window_index: AtomicU64,
+}
+
+impl UniqueCounter {
+    /// # Panics
+    ///
+    /// This function panics if precision is out of
+    /// `MIN_PRECISION..=MAX_PRECISION`.
+    pub(crate) fn new(precision: u8, window: Option<Duration>) -> UniqueCounter {
+        assert!(
+            (MIN_PRECISION..=MAX_PRECISION).contains(&precision),
+            "Precision must be in range of {} to {}.",
+            MIN_PRECISION,
+            MAX_PRECISION
+        );
+
+        let new_registers = |len: usize| (0..len).map(|_| AtomicU8::new(0)).collect();
+        let len = 1usize << precision;
+        UniqueCounter {
+            precision,
+            // registers of the last window are only needed with a window
+            registers: [
+                new_registers(len),
+                new_registers(if window.is_some() { len } else { 0 }),
+            ],
+            current: AtomicUsize::new(0),
+            window,
+            created_at: Instant::now(),
+            window_index: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a value.
+    pub fn observe<T: Hash + ?Sized>(&self, value: &T) {
+        self.maybe_rotate();
+
+        let hash = hash(value);
+
+        let idx = (hash >> (64 - self.precision)) as usize;
+        // position of the first 1 bit in remaining bits, the appended bit
+        // bounds the rank when they are all zero
+        let rank = ((hash << self.precision) | (1 << (self.precision - 1))).leading_zeros() + 1;
+        self.current()[idx].fetch_max(rank as u8, Ordering::Relaxed);
+    }
+
+    /// Merge values observed by another unique counter, in its current
+    /// window, into the current window of this one.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the other counter has different precision.
+    pub fn merge(&self, other: &UniqueCounter) {
+        assert_eq!(
+            self.precision, other.precision,
+            "Unique counters with different precision can not be merged."
+        );
+
+        self.maybe_rotate();
+        other.maybe_rotate();
+        for (r, o) in self.current().iter().zip(other.current().iter()) {
+            r.fetch_max(o.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Estimated number of distinct values, observed in the last completed
+    /// window if the counter has a window.
+    pub fn value(&self) -> u64 {
+        self.maybe_rotate();
+
+        let registers = if self.window.is_some() {
+            &self.registers[1 - self.current.load(Ordering::Relaxed)]
+        } else {
+            self.current()
+        };
+        estimate(registers)
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    pub fn window(&self) -> Option<Duration> {
+        self.window
+    }
+
+    /// Forget all observed values.
+    pub fn clear(&self) {
+        for registers in self.registers.iter() {
+            clear(registers);
+        }
+    }
+
+    fn current(&self) -> &[AtomicU8] {
+        &self.registers[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Switch registers when a new window starts, windows are aligned to the
+    /// creation of the counter.
+    fn maybe_rotate(&self) {
+        if let Some(window) = self.window {
+            let window_ms = (window.as_millis() as u64).max(1);
+            let index = self.created_at.elapsed().as_millis() as u64 / window_ms;
+            let last = self.window_index.load(Ordering::Relaxed);
+            if index > last
+                && self
+                    .window_index
+                    .compare_exchange(last, index, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                let current = self.current.load(Ordering::Relaxed);
+                clear(&self.registers[1 - current]);
+                if index > last + 1 {
+                    // nothing observed in the last completed window
+                    clear(&self.registers[current]);
+                }
+                self.current.store(1 - current, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn clear(registers: &[AtomicU8]) {
+    for r in registers.iter() {
+        r.store(0, Ordering::Relaxed);
+    }
+}
+
+fn estimate(registers: &[AtomicU8]) -> u64 {
+    let m = registers.len() as f64;
+    let mut zeros = 0;
+    let mut sum = 0f64;
+    for r in registers.iter() {
+        let rank = r.load(Ordering::Relaxed);
+        if rank == 0 {
+            zeros += 1;
+        }
+        sum += 2f64.powi(-(rank as i32));
+    }
+
+    let alpha = match registers.len() {
+        16 => 0.673,
+        32 => 0.697,
+        64 => 0.709,
+        _ => 0.7213 / (1.0 + 1.079 / m),
+    };
+    let estimate = alpha * m * m / sum;
+
+    // linear counting is more accurate for small cardinality
+    if estimate <= 2.5 * m && zeros > 0 {
+        (m * (m / zeros as f64).ln()).round() as u64
+    } else {
+        estimate.round() as u64
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0, 0);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(feature = "ser")]
+impl Serialize for UniqueCounter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("value", &self.value())?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{hash, UniqueCounter};
+
+    #[test]
+    fn test_unique_counter() {
+        let counter = UniqueCounter::new(12, None);
+        assert_eq!(0, counter.value());
+
+        for i in 0..10000 {
+            counter.observe(&(i % 5000));
+        }
+        let value = counter.value() as f64;
+        assert!((value - 5000.0).abs() / 5000.0 < 0.05, "{}", value);
+
+        let other = UniqueCounter::new(12, None);
+        for i in 2500..7500 {
+            other.observe(&i);
+        }
+        counter.merge(&other);
+        let value = counter.value() as f64;
+        assert!((value - 7500.0).abs() / 7500.0 < 0.05, "{}", value);
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        // must not change across releases, or merged counters overcount
+        assert_eq!(8880661182590738257, hash(&42u64));
+    }
+
+    #[test]
+    fn test_windowed_unique_counter() {
+        let counter = UniqueCounter::new(8, Some(Duration::from_millis(100)));
+        counter.observe("10.0.0.1");
+        counter.observe("10.0.0.2");
+        // the first window is not completed yet
+        assert_eq!(0, counter.value());
+
+        std::thread::sleep(Duration::from_millis(100));
+        counter.observe("10.0.0.3");
+        // the estimate of the last window is reported, however often it's read
+        assert_eq!(2, counter.value());
+        assert_eq!(2, counter.value());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(1, counter.value());
+
+        // nothing observed in the last window
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(0, counter.value());
+    }
+}
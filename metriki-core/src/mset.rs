@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 
+use crate::key::Key;
 use crate::metrics::Metric;
 
 /// The `MetricsSet` trait defines a structure that provides
@@ -10,9 +11,19 @@ use crate::metrics::Metric;
 /// static ones. Once it was created the registry holds and tracks
 /// it automatically. in contrast, `MetricsSet` is pulled by registry
 /// to provide metrics everytime. This is useful to implement features
-/// like "Top 10 APIs".
+/// like "Top 10 APIs", see `TopK`.
 pub trait MetricsSet: Send + Sync + Debug {
     fn get_all(&self) -> HashMap<String, Metric>;
+
+    /// Metrics with tags, pulled by registry.
+    ///
+    /// By default, it returns metrics from `get_all` without tags.
+    fn get_all_with_tags(&self) -> HashMap<Key, Metric> {
+        self.get_all()
+            .into_iter()
+            .map(|(name, metric)| (Key::from_name(&name), metric))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -49,9 +60,7 @@ mod test {
         let snapshots = registry.snapshots();
 
         assert_eq!(2, snapshots.len());
-        assert!(snapshots.get(&Key::from_name("test.set.counter")).is_some());
-        assert!(snapshots
-            .get(&Key::from_name("test.default.counter"))
-            .is_some());
+        assert!(snapshots.contains_key(&Key::from_name("test.set.counter")));
+        assert!(snapshots.contains_key(&Key::from_name("test.default.counter")));
     }
 }
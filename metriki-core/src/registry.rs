@@ -1,13 +1,18 @@
 use dashmap::DashMap;
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(feature = "ser")]
 use serde::ser::SerializeMap;
 #[cfg(feature = "ser")]
 use serde::{Serialize, Serializer};
 
+use crate::family::{
+    CounterFamily, HistogramFamily, MeterFamily, MetricFamily, MonotonicCounterFamily, TimerFamily,
+};
 use crate::filter::MetricsFilter;
 use crate::key::{Key, Tag};
 use crate::metrics::*;
@@ -30,9 +35,119 @@ impl Debug for MetricsRegistry {
 }
 
 #[derive(Default, Debug)]
-struct Inner {
+pub(crate) struct Inner {
     metrics: DashMap<Key, Metric>,
     mset: DashMap<String, Arc<dyn MetricsSet + 'static>>,
+    histogram_stripes: AtomicUsize,
+    counter_stripes: AtomicUsize,
+}
+
+impl Inner {
+    /// Return the metric registered with `key`, or register the one built by `create`.
+    ///
+    /// `cast` extracts the metric of expected type, a registered metric of other type
+    /// is a panic.
+    fn get_or_insert<M>(
+        &self,
+        key: Key,
+        create: impl FnOnce() -> Arc<M>,
+        cast: fn(&Metric) -> Option<Arc<M>>,
+    ) -> Arc<M>
+    where
+        Arc<M>: Into<Metric>,
+    {
+        let registered = self.metrics.get(&key).map(|metric| cast(metric.value()));
+        let metric = match registered {
+            Some(m) => m,
+            None => cast(
+                self.metrics
+                    .entry(key)
+                    .or_insert_with(|| create().into())
+                    .value(),
+            ),
+        };
+
+        metric.expect("A metric with same name and different type is already registered.")
+    }
+
+    pub(crate) fn meter(&self, key: Key) -> Arc<Meter> {
+        let stripes = self.counter_stripes.load(Ordering::Relaxed);
+        self.get_or_insert(
+            key,
+            || Arc::new(Meter::with_stripes(stripes)),
+            Metric::as_meter,
+        )
+    }
+
+    pub(crate) fn histogram(&self, key: Key) -> Arc<Histogram> {
+        let stripes = self.histogram_stripes.load(Ordering::Relaxed);
+        self.get_or_insert(
+            key,
+            || Arc::new(Histogram::with_stripes(stripes)),
+            Metric::as_histogram,
+        )
+    }
+
+    pub(crate) fn counter(&self, key: Key) -> Arc<Counter> {
+        let stripes = self.counter_stripes.load(Ordering::Relaxed);
+        self.get_or_insert(
+            key,
+            || Arc::new(Counter::with_stripes(stripes)),
+            Metric::as_counter,
+        )
+    }
+
+    pub(crate) fn bucketed_histogram(&self, key: Key, buckets: Buckets) -> Arc<BucketedHistogram> {
+        self.get_or_insert(
+            key,
+            || Arc::new(BucketedHistogram::new(buckets)),
+            Metric::as_bucketed_histogram,
+        )
+    }
+
+    pub(crate) fn exponential_histogram(&self, key: Key, scale: i32) -> Arc<ExponentialHistogram> {
+        self.get_or_insert(
+            key,
+            || Arc::new(ExponentialHistogram::new(scale)),
+            Metric::as_exponential_histogram,
+        )
+    }
+
+    pub(crate) fn apdex(&self, key: Key, threshold: Duration) -> Arc<Apdex> {
+        self.get_or_insert(key, || Arc::new(Apdex::new(threshold)), Metric::as_apdex)
+    }
+
+    pub(crate) fn unique_counter(
+        &self,
+        key: Key,
+        precision: u8,
+        window: Option<Duration>,
+    ) -> Arc<UniqueCounter> {
+        self.get_or_insert(
+            key,
+            || Arc::new(UniqueCounter::new(precision, window)),
+            Metric::as_unique_counter,
+        )
+    }
+
+    pub(crate) fn monotonic_counter(&self, key: Key) -> Arc<MonotonicCounter> {
+        let stripes = self.counter_stripes.load(Ordering::Relaxed);
+        self.get_or_insert(
+            key,
+            || Arc::new(MonotonicCounter::with_stripes(stripes)),
+            Metric::as_monotonic_counter,
+        )
+    }
+
+    pub(crate) fn timer(&self, key: Key) -> Arc<Timer> {
+        let histogram_stripes = self.histogram_stripes.load(Ordering::Relaxed);
+        let counter_stripes = self.counter_stripes.load(Ordering::Relaxed);
+        self.get_or_insert(
+            key,
+            || Arc::new(Timer::with_stripes(histogram_stripes, counter_stripes)),
+            Metric::as_timer,
+        )
+    }
 }
 
 impl MetricsRegistry {
@@ -56,38 +171,14 @@ impl MetricsRegistry {
     /// This function may panic if a metric is already registered with type other than meter.
     pub fn meter(&self, name: &str) -> Arc<Meter> {
         let key = Key::from_name(name);
-        self.do_meter(key)
+        self.inner.meter(key)
     }
 
     pub fn meter_with_tags(&self, name: &str, tags: Vec<Tag>) -> Arc<Meter> {
         let key = Key::from(name, tags);
-        self.do_meter(key)
-    }
-
-    fn do_meter(&self, key: Key) -> Arc<Meter> {
-        let meter = {
-            self.inner
-                .metrics
-                .get(&key)
-                .as_deref()
-                .map(|metric| match metric {
-                    Metric::Meter(ref m) => m.clone(),
-                    _ => {
-                        panic!("A metric with same name and different type is already registered.")
-                    }
-                })
-        };
-
-        if let Some(m) = meter {
-            m
-        } else {
-            let meter = Arc::new(Meter::new());
-            self.inner.metrics.insert(key, Metric::Meter(meter.clone()));
-            meter
-        }
+        self.inner.meter(key)
     }
 
-
     /// Return `Histogram` that has been registered and create if not found.
     ///
     /// Histogram a metric to measure distribution of a series of data. The distribution will
@@ -98,79 +189,152 @@ impl MetricsRegistry {
     /// This function may panic if a metric is already registered with type other than histogram.
     pub fn histogram(&self, name: &str) -> Arc<Histogram> {
         let key = Key::from_name(name);
-        self.do_histogram(key)
+        self.inner.histogram(key)
     }
 
     pub fn histogram_with_tags(&self, name: &str, tags: Vec<Tag>) -> Arc<Histogram> {
         let key = Key::from(name, tags);
-        self.do_histogram(key)
-    }
-
-    fn do_histogram(&self, key: Key) -> Arc<Histogram> {
-        let histo = {
-            self.inner
-                .metrics
-                .get(&key)
-                .as_deref()
-                .map(|metric| match metric {
-                    Metric::Histogram(ref m) => m.clone(),
-                    _ => {
-                        panic!("A metric with same name and different type is already registered.")
-                    }
-                })
-        };
+        self.inner.histogram(key)
+    }
 
-        if let Some(m) = histo {
-            m
-        } else {
-            let histo = Arc::new(Histogram::new());
-            self.inner
-                .metrics
-                .insert(key, Metric::Histogram(histo.clone()));
-            histo
-        }
+    /// Return `BucketedHistogram` that has been registered and create if not found.
+    ///
+    /// BucketedHistogram a metric to count samples into fixed buckets. Unlike `Histogram`,
+    /// bucket counts of multiple instances can be aggregated. If the histogram is already
+    /// registered, `buckets` is ignored and the existing histogram is returned.
+    ///
+    /// # Panics
+    ///
+    /// This function may panic if a metric is already registered with type other than
+    /// bucketed histogram.
+    pub fn bucketed_histogram(&self, name: &str, buckets: Buckets) -> Arc<BucketedHistogram> {
+        let key = Key::from_name(name);
+        self.inner.bucketed_histogram(key, buckets)
+    }
+
+    pub fn bucketed_histogram_with_tags(
+        &self,
+        name: &str,
+        tags: Vec<Tag>,
+        buckets: Buckets,
+    ) -> Arc<BucketedHistogram> {
+        let key = Key::from(name, tags);
+        self.inner.bucketed_histogram(key, buckets)
+    }
+
+    /// Return `ExponentialHistogram` that has been registered and create if not found.
+    ///
+    /// ExponentialHistogram a metric to measure distribution with base-2 exponential buckets,
+    /// like Prometheus native histogram. `scale` is the initial resolution, in range of
+    /// `MIN_EXPONENTIAL_SCALE..=MAX_EXPONENTIAL_SCALE`. If the histogram is already
+    /// registered, `scale` is ignored.
+    ///
+    /// # Panics
+    ///
+    /// This function may panic if a metric is already registered with type other than
+    /// exponential histogram, or the scale is out of range.
+    pub fn exponential_histogram(&self, name: &str, scale: i32) -> Arc<ExponentialHistogram> {
+        let key = Key::from_name(name);
+        self.inner.exponential_histogram(key, scale)
+    }
+
+    pub fn exponential_histogram_with_tags(
+        &self,
+        name: &str,
+        tags: Vec<Tag>,
+        scale: i32,
+    ) -> Arc<ExponentialHistogram> {
+        let key = Key::from(name, tags);
+        self.inner.exponential_histogram(key, scale)
     }
 
     /// Return `Counter` that has been registered and create if not found.
     ///
-    /// Counter a metric to measure the number of some state.
+    /// Counter a metric to measure the number of some state, which can go up and down.
+    /// It's reported as a gauge. For totals that only go up, use `monotonic_counter`.
     ///
     /// # Panics
     ///
     /// This function may panic if a metric is already registered with type other than counter.
     pub fn counter(&self, name: &str) -> Arc<Counter> {
         let key = Key::from_name(name);
-        self.do_counter(key)
+        self.inner.counter(key)
     }
 
     pub fn counter_with_tags(&self, name: &str, tags: Vec<Tag>) -> Arc<Counter> {
         let key = Key::from(name, tags);
-        self.do_counter(key)
-    }
-
-    fn do_counter(&self, key: Key) -> Arc<Counter> {
-        let counter = {
-            self.inner
-                .metrics
-                .get(&key)
-                .as_deref()
-                .map(|metric| match metric {
-                    Metric::Counter(ref m) => m.clone(),
-                    _ => {
-                        panic!("A metric with same name and different type is already registered.")
-                    }
-                })
-        };
+        self.inner.counter(key)
+    }
 
-        if let Some(m) = counter {
-            m
-        } else {
-            let counter = Arc::new(Counter::new());
-            self.inner
-                .metrics
-                .insert(key, Metric::Counter(counter.clone()));
-            counter
-        }
+    /// Return `UniqueCounter` that has been registered and create if not found.
+    ///
+    /// UniqueCounter a metric to estimate the number of distinct values, like unique users,
+    /// with HyperLogLog. `precision` is in range of
+    /// `MIN_UNIQUE_PRECISION..=MAX_UNIQUE_PRECISION`, higher precision uses more memory for
+    /// less error. With a `window`, the value is the estimate of the last completed window.
+    /// If the counter is already registered, `precision` and `window` are ignored.
+    ///
+    /// # Panics
+    ///
+    /// This function may panic if a metric is already registered with type other than
+    /// unique counter, or the precision is out of range.
+    pub fn unique_counter(
+        &self,
+        name: &str,
+        precision: u8,
+        window: Option<Duration>,
+    ) -> Arc<UniqueCounter> {
+        let key = Key::from_name(name);
+        self.inner.unique_counter(key, precision, window)
+    }
+
+    pub fn unique_counter_with_tags(
+        &self,
+        name: &str,
+        tags: Vec<Tag>,
+        precision: u8,
+        window: Option<Duration>,
+    ) -> Arc<UniqueCounter> {
+        let key = Key::from(name, tags);
+        self.inner.unique_counter(key, precision, window)
+    }
+
+    /// Return `Apdex` that has been registered and create if not found.
+    ///
+    /// Apdex a metric to measure satisfaction of response time against `threshold`.
+    /// If the apdex is already registered, `threshold` is ignored. To track apdex of
+    /// a timer, use `Timer::enable_apdex`.
+    ///
+    /// # Panics
+    ///
+    /// This function may panic if a metric is already registered with type other than apdex.
+    pub fn apdex(&self, name: &str, threshold: Duration) -> Arc<Apdex> {
+        let key = Key::from_name(name);
+        self.inner.apdex(key, threshold)
+    }
+
+    pub fn apdex_with_tags(&self, name: &str, tags: Vec<Tag>, threshold: Duration) -> Arc<Apdex> {
+        let key = Key::from(name, tags);
+        self.inner.apdex(key, threshold)
+    }
+
+    /// Return `MonotonicCounter` that has been registered and create if not found.
+    ///
+    /// MonotonicCounter a metric to measure the total number of some event. It can only
+    /// be increased, and is reported as a counter.
+    ///
+    /// # Panics
+    ///
+    /// This function may panic if a metric is already registered with type other than
+    /// monotonic counter.
+    pub fn monotonic_counter(&self, name: &str) -> Arc<MonotonicCounter> {
+        let key = Key::from_name(name);
+        self.inner.monotonic_counter(key)
+    }
+
+    pub fn monotonic_counter_with_tags(&self, name: &str, tags: Vec<Tag>) -> Arc<MonotonicCounter> {
+        let key = Key::from(name, tags);
+        self.inner.monotonic_counter(key)
     }
 
     /// Return `Timer` that has been registered and create if not found.
@@ -183,35 +347,49 @@ impl MetricsRegistry {
     /// This function may panic if a metric is already registered with type other than counter.
     pub fn timer(&self, name: &str) -> Arc<Timer> {
         let key = Key::from_name(name);
-        self.do_timer(key)
+        self.inner.timer(key)
     }
 
     pub fn timer_with_tags(&self, name: &str, tags: Vec<Tag>) -> Arc<Timer> {
         let key = Key::from(name, tags);
-        self.do_timer(key)
-    }
-
-    fn do_timer(&self, key: Key) -> Arc<Timer> {
-        let timer = {
-            self.inner
-                .metrics
-                .get(&key)
-                .as_deref()
-                .map(|metric| match metric {
-                    Metric::Timer(ref m) => m.clone(),
-                    _ => {
-                        panic!("A metric with same name and different type is already registered.")
-                    }
-                })
-        };
+        self.inner.timer(key)
+    }
 
-        if let Some(m) = timer {
-            m
-        } else {
-            let timer = Arc::new(Timer::new());
-            self.inner.metrics.insert(key, Metric::Timer(timer.clone()));
-            timer
-        }
+    /// Return a `CounterFamily` of counters that share `name` and label keys.
+    ///
+    /// Counters in the family are registered as tagged counters, one for each
+    /// combination of label values.
+    pub fn counter_family(&self, name: &str, label_keys: &[&str]) -> CounterFamily {
+        MetricFamily::new(self.inner.clone(), name, label_keys, Inner::counter)
+    }
+
+    /// Return a `MonotonicCounterFamily` of monotonic counters that share `name` and label keys.
+    pub fn monotonic_counter_family(
+        &self,
+        name: &str,
+        label_keys: &[&str],
+    ) -> MonotonicCounterFamily {
+        MetricFamily::new(
+            self.inner.clone(),
+            name,
+            label_keys,
+            Inner::monotonic_counter,
+        )
+    }
+
+    /// Return a `MeterFamily` of meters that share `name` and label keys.
+    pub fn meter_family(&self, name: &str, label_keys: &[&str]) -> MeterFamily {
+        MetricFamily::new(self.inner.clone(), name, label_keys, Inner::meter)
+    }
+
+    /// Return a `HistogramFamily` of histograms that share `name` and label keys.
+    pub fn histogram_family(&self, name: &str, label_keys: &[&str]) -> HistogramFamily {
+        MetricFamily::new(self.inner.clone(), name, label_keys, Inner::histogram)
+    }
+
+    /// Return a `TimerFamily` of timers that share `name` and label keys.
+    pub fn timer_family(&self, name: &str, label_keys: &[&str]) -> TimerFamily {
+        MetricFamily::new(self.inner.clone(), name, label_keys, Inner::timer)
     }
 
     /// Register a `Gauge` with given function.
@@ -249,10 +427,10 @@ impl MetricsRegistry {
         }
         let mset = self.inner.mset.clone();
         for metrics_set in mset.into_read_only().values() {
-            let metrics = metrics_set.get_all();
-            for (k, v) in metrics.iter() {
-                if filter.map(|f| f.accept(k, v)).unwrap_or(true) {
-                    results.insert(Key::from_name(k), v.clone());
+            let metrics = metrics_set.get_all_with_tags();
+            for (k, v) in metrics.into_iter() {
+                if filter.map(|f| f.accept(k.key(), &v)).unwrap_or(true) {
+                    results.insert(k, v);
                 }
             }
         }
@@ -267,6 +445,33 @@ impl MetricsRegistry {
         self.filter = filter;
     }
 
+    /// Set number of stripes for histograms and timers created from this registry.
+    ///
+    /// By default, all threads record samples into a single HdrHistogram behind a
+    /// lock. With stripes, threads record into one of several HdrHistograms, which
+    /// are merged when a snapshot is taken. This reduces lock contention on hot
+    /// paths at the cost of memory. A value of 0 or 1 disables striping.
+    ///
+    /// Only histograms and timers created after this call are affected.
+    pub fn set_histogram_stripes(&self, stripes: usize) {
+        self.inner
+            .histogram_stripes
+            .store(stripes, Ordering::Relaxed);
+    }
+
+    /// Set number of stripes for counters and meters created from this registry,
+    /// including meters of timers.
+    ///
+    /// By default, a counter is a single atomic value that all threads update.
+    /// With stripes, threads update one of several cache padded cells, which
+    /// are summed when the value is read. This avoids false sharing when many
+    /// cores increase the same counter. A value of 0 or 1 disables striping.
+    ///
+    /// Only metrics created after this call are affected.
+    pub fn set_counter_stripes(&self, stripes: usize) {
+        self.inner.counter_stripes.store(stripes, Ordering::Relaxed);
+    }
+
     /// Register a MetricsSet implementation.
     ///
     /// A MetricsSet returns a set of metrics when `snapshots()` is called on
@@ -285,6 +490,23 @@ impl MetricsRegistry {
     }
 }
 
+#[cfg(feature = "ser")]
+impl Serialize for MetricsRegistry {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let snapshot = self.snapshots();
+        let mut map = serializer.serialize_map(Some(snapshot.len()))?;
+
+        for (k, v) in snapshot.iter() {
+            map.serialize_entry(k, v)?;
+        }
+
+        map.end()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::filter::MetricsFilter;
@@ -312,21 +534,26 @@ mod test {
         let snapshot = registry.snapshots();
         assert_eq!(2, snapshot.len());
     }
-}
 
-#[cfg(feature = "ser")]
-impl Serialize for MetricsRegistry {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-    {
-        let snapshot = self.snapshots();
-        let mut map = serializer.serialize_map(Some(snapshot.len()))?;
+    #[test]
+    fn test_monotonic_counter() {
+        let registry = MetricsRegistry::new();
 
-        for (k, v) in snapshot.iter() {
-            map.serialize_entry(k, v)?;
-        }
+        registry.monotonic_counter("requests.total").inc(2);
+        registry.monotonic_counter("requests.total").inc(3);
+        registry.counter("requests.inflight").inc(1);
 
-        map.end()
+        let snapshot = registry.snapshots();
+        let total = snapshot
+            .iter()
+            .find_map(|(k, m)| {
+                m.as_monotonic_counter()
+                    .filter(|_| k.key() == "requests.total")
+            })
+            .unwrap();
+        assert_eq!(5, total.value());
+        assert!(snapshot
+            .iter()
+            .all(|(k, m)| k.key() != "requests.inflight" || m.as_monotonic_counter().is_none()));
     }
 }
@@ -0,0 +1,912 @@
+//! Common runtime for push based reporters.
+//!
+//! A reporter implements [`Reporter`] to send a snapshot of metrics to its
+//! destination, and [`ScheduledReporter`] runs it periodically on a
+//! background thread.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::key::{Key, Tag};
+use crate::metrics::{Metric, MonotonicCounter, Timer};
+use crate::registry::MetricsRegistry;
+
+/// Metrics of a registry at some point, as returned by `MetricsRegistry::snapshots`.
+pub type Snapshot = HashMap<Key, Metric>;
+
+/// Error returned by reporters.
+pub type ReportError = Box<dyn Error + Send + Sync>;
+
+/// A reporter sends snapshots of metrics to some destination.
+pub trait Reporter: Send + 'static {
+    /// Send the snapshot.
+    ///
+    /// Reporters may keep state between calls, like last reported counts.
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError>;
+
+    /// Send the last snapshot before the reporter stops.
+    ///
+    /// There is no later report to retry in, so reporters buffering failed
+    /// points should try them now instead of waiting for backoff. It calls
+    /// `report` by default.
+    fn report_final(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        self.report(snapshot)
+    }
+}
+
+type ErrorCallback = Box<dyn Fn(&ReportError) + Send + Sync + 'static>;
+
+/// Runs a `Reporter` periodically on a background thread.
+///
+/// Metrics are reported at start, then every `interval` plus a random
+/// jitter up to `jitter`, so reporters of many instances don't hit the
+/// destination at the same time. When stopped, a final report is sent
+/// before the thread exits. See `ReporterHandle` for controlling the
+/// started reporter.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// use metriki_core::reporter::{ReportError, Reporter, ScheduledReporter, Snapshot};
+/// use metriki_core::MetricsRegistry;
+///
+/// struct PrintReporter;
+///
+/// impl Reporter for PrintReporter {
+///     fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+///         println!("{:?}", snapshot);
+///         Ok(())
+///     }
+/// }
+///
+/// let registry = Arc::new(MetricsRegistry::new());
+/// let handle = ScheduledReporter::new(registry, PrintReporter, Duration::from_secs(30))
+///     .jitter(Duration::from_secs(5))
+///     .on_error(|e| eprintln!("Failed to report metrics: {}", e))
+///     .start();
+///
+/// // report now, for example before a risky operation
+/// handle.flush();
+///
+/// // stop after a final report, and wait for it
+/// handle.stop();
+/// handle.join();
+/// ```
+pub struct ScheduledReporter<R> {
+    registry: Arc<MetricsRegistry>,
+    reporter: R,
+    interval: Duration,
+    jitter: Duration,
+    on_error: Option<ErrorCallback>,
+}
+
+impl<R> Debug for ScheduledReporter<R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("ScheduledReporter")
+            .field("interval", &self.interval)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+enum Command {
+    Flush(u64),
+    Stop,
+}
+
+/// Progress of a started reporter, waited on by handles and futures.
+#[derive(Debug, Default)]
+struct Progress {
+    state: Mutex<ProgressState>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    requested: u64,
+    flushed: u64,
+    stopped: bool,
+    wakers: Vec<Waker>,
+}
+
+impl ProgressState {
+    /// Whether the flush is done, or the reporter stopped when `flush` is `None`.
+    fn is_done(&self, flush: Option<u64>) -> bool {
+        match flush {
+            Some(flush) => self.stopped || self.flushed >= flush,
+            None => self.stopped,
+        }
+    }
+}
+
+impl Progress {
+    fn update<F: FnOnce(&mut ProgressState)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+        self.cond.notify_all();
+    }
+
+    fn wait(&self, flush: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        while !state.is_done(flush) {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+}
+
+/// Future that completes when the reporter has finished a flush or stopped.
+#[derive(Debug)]
+pub struct SignalFuture {
+    progress: Arc<Progress>,
+    flush: Option<u64>,
+}
+
+impl Future for SignalFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.progress.state.lock().unwrap();
+        if state.is_done(self.flush) {
+            Poll::Ready(())
+        } else {
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Mark the reporter stopped on drop, so waiters are woken even if the
+/// reporter panics.
+struct StopOnDrop(Arc<Progress>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.update(|state| state.stopped = true);
+    }
+}
+
+/// Handle of a started reporter.
+///
+/// The handle can be cloned and shared, for example with a shutdown hook.
+/// When the last handle is dropped, the reporter is stopped after a final
+/// report, and the drop blocks until it's sent. Call `stop` and
+/// `join_async` to avoid blocking in async context.
+#[must_use = "the reporter is stopped when its handle is dropped"]
+#[derive(Debug, Clone)]
+pub struct ReporterHandle {
+    inner: Arc<HandleInner>,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    sender: Sender<Command>,
+    progress: Arc<Progress>,
+}
+
+impl Drop for HandleInner {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Stop);
+        self.progress.wait(None);
+    }
+}
+
+impl<R: Reporter> ScheduledReporter<R> {
+    pub fn new(registry: Arc<MetricsRegistry>, reporter: R, interval: Duration) -> Self {
+        ScheduledReporter {
+            registry,
+            reporter,
+            interval,
+            jitter: Duration::ZERO,
+            on_error: None,
+        }
+    }
+
+    /// Add a random delay up to `jitter` to each interval.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the function called when the reporter returns an error.
+    ///
+    /// Errors are ignored by default.
+    pub fn on_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ReportError) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    /// Start reporting in a background thread.
+    pub fn start(self) -> ReporterHandle {
+        let (sender, receiver) = mpsc::channel();
+        let progress = Arc::new(Progress::default());
+        let guard = StopOnDrop(progress.clone());
+
+        thread::Builder::new()
+            .name("metriki-reporter".to_owned())
+            .spawn(move || {
+                let _guard = guard;
+                let mut this = self;
+                let random = RandomState::new();
+                let mut round = 0u64;
+                let mut next = Instant::now();
+
+                loop {
+                    let now = Instant::now();
+                    if now >= next {
+                        this.report(false);
+                        round += 1;
+                        next = now + this.interval + this.random_jitter(&random, round);
+                        continue;
+                    }
+
+                    match receiver.recv_timeout(next - now) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Ok(Command::Flush(flush)) => {
+                            this.report(false);
+                            _guard
+                                .0
+                                .update(|state| state.flushed = state.flushed.max(flush));
+                        }
+                        Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                            this.report(true);
+                            return;
+                        }
+                    }
+                }
+            })
+            .expect("Failed to start reporter thread.");
+
+        ReporterHandle {
+            inner: Arc::new(HandleInner { sender, progress }),
+        }
+    }
+
+    fn report(&mut self, last: bool) {
+        let snapshot = self.registry.snapshots();
+        let result = if last {
+            self.reporter.report_final(&snapshot)
+        } else {
+            self.reporter.report(&snapshot)
+        };
+        if let Err(e) = result {
+            if let Some(on_error) = self.on_error.as_ref() {
+                on_error(&e);
+            }
+        }
+    }
+
+    fn random_jitter(&self, random: &RandomState, round: u64) -> Duration {
+        let jitter_nanos = self.jitter.as_nanos() as u64;
+        if jitter_nanos == 0 {
+            return Duration::ZERO;
+        }
+
+        // randomly keyed hash of the round number as random source
+        let mut hasher = random.build_hasher();
+        hasher.write_u64(round);
+        Duration::from_nanos(hasher.finish() % jitter_nanos)
+    }
+}
+
+impl ReporterHandle {
+    /// Ask the reporter to stop after a final report, without waiting for it.
+    pub fn stop(&self) {
+        let _ = self.inner.sender.send(Command::Stop);
+    }
+
+    /// Report metrics now, and wait until it's done.
+    pub fn flush(&self) {
+        if let Some(flush) = self.request_flush() {
+            self.inner.progress.wait(Some(flush));
+        }
+    }
+
+    /// Report metrics now, the returned future completes when it's done.
+    pub fn flush_async(&self) -> SignalFuture {
+        SignalFuture {
+            progress: self.inner.progress.clone(),
+            flush: self.request_flush(),
+        }
+    }
+
+    /// Wait until the reporter is stopped and its final report is sent.
+    ///
+    /// This blocks until `stop` is called on any handle of the reporter.
+    pub fn join(&self) {
+        self.inner.progress.wait(None);
+    }
+
+    /// Returns a future that completes when the reporter is stopped and its
+    /// final report is sent.
+    pub fn join_async(&self) -> SignalFuture {
+        SignalFuture {
+            progress: self.inner.progress.clone(),
+            flush: None,
+        }
+    }
+
+    /// Returns `None` if the reporter is already stopped.
+    fn request_flush(&self) -> Option<u64> {
+        let mut state = self.inner.progress.state.lock().unwrap();
+        if state.stopped {
+            return None;
+        }
+        state.requested += 1;
+        let flush = state.requested;
+        // a stopped reporter may still accept the command, which is fine as
+        // waiters also check `stopped`
+        let _ = self.inner.sender.send(Command::Flush(flush));
+        Some(flush)
+    }
+}
+
+/// Options of `RetryBuffer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryOptions {
+    /// Max number of points kept for delivery. When it's full, oldest points
+    /// are dropped.
+    pub capacity: usize,
+    /// Delay before the first retry, doubled on each consecutive failure.
+    pub initial_backoff: Duration,
+    /// Upper bound of the retry delay.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> RetryOptions {
+        RetryOptions {
+            capacity: 10000,
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Counters of points delivered by a `RetryBuffer`, registered as monotonic
+/// counters `{prefix}.sent`, `{prefix}.failed`, `{prefix}.retried` and
+/// `{prefix}.dropped`.
+#[derive(Debug, Clone)]
+pub struct DeliveryMetrics {
+    sent: Arc<MonotonicCounter>,
+    failed: Arc<MonotonicCounter>,
+    retried: Arc<MonotonicCounter>,
+    dropped: Arc<MonotonicCounter>,
+}
+
+impl DeliveryMetrics {
+    pub fn new(registry: &MetricsRegistry, prefix: &str) -> DeliveryMetrics {
+        DeliveryMetrics {
+            sent: registry.monotonic_counter(&format!("{}.sent", prefix)),
+            failed: registry.monotonic_counter(&format!("{}.failed", prefix)),
+            retried: registry.monotonic_counter(&format!("{}.retried", prefix)),
+            dropped: registry.monotonic_counter(&format!("{}.dropped", prefix)),
+        }
+    }
+
+    /// Points delivered successfully.
+    pub fn sent(&self) -> u64 {
+        self.sent.value()
+    }
+
+    /// Points in failed attempts, counted on each attempt.
+    pub fn failed(&self) -> u64 {
+        self.failed.value()
+    }
+
+    /// Points attempted again after a failure.
+    pub fn retried(&self) -> u64 {
+        self.retried.value()
+    }
+
+    /// Points dropped because the buffer was full, or rejected by the
+    /// destination.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.value()
+    }
+}
+
+/// Metrics of a reporter about itself, registered as
+///
+/// - `{prefix}.duration`: timer of reports
+/// - `{prefix}.points`: monotonic counter of points emitted
+/// - `{prefix}.bytes`: monotonic counter of bytes sent
+/// - `{prefix}.errors`: monotonic counters of failed reports, tagged with
+///   error `type`
+/// - `{prefix}.last_success`: gauge of unix time in seconds of the last
+///   successful report, 0 if there is none
+#[derive(Debug, Clone)]
+pub struct ReporterMetrics {
+    registry: Arc<MetricsRegistry>,
+    prefix: String,
+    duration: Arc<Timer>,
+    points: Arc<MonotonicCounter>,
+    bytes: Arc<MonotonicCounter>,
+    last_success: Arc<AtomicU64>,
+}
+
+impl ReporterMetrics {
+    pub fn new(registry: Arc<MetricsRegistry>, prefix: &str) -> ReporterMetrics {
+        let last_success = Arc::new(AtomicU64::new(0));
+        let last_success_ref = last_success.clone();
+        registry.gauge(
+            &format!("{}.last_success", prefix),
+            Box::new(move || last_success_ref.load(Ordering::Relaxed) as f64),
+        );
+
+        ReporterMetrics {
+            duration: registry.timer(&format!("{}.duration", prefix)),
+            points: registry.monotonic_counter(&format!("{}.points", prefix)),
+            bytes: registry.monotonic_counter(&format!("{}.bytes", prefix)),
+            last_success,
+            prefix: prefix.to_owned(),
+            registry,
+        }
+    }
+
+    pub fn add_points(&self, n: u64) {
+        self.points.inc(n);
+    }
+
+    pub fn add_bytes(&self, n: u64) {
+        self.bytes.inc(n);
+    }
+
+    pub fn success(&self, duration: Duration) {
+        self.duration.update(duration);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.last_success.store(now, Ordering::Relaxed);
+    }
+
+    pub fn failure(&self, duration: Duration, error_type: &str) {
+        self.duration.update(duration);
+        self.registry
+            .monotonic_counter_with_tags(
+                &format!("{}.errors", self.prefix),
+                vec![Tag::new("type", error_type)],
+            )
+            .inc(1);
+    }
+
+    /// Record a report by its result, with errors classified by `error_type`.
+    pub fn record(&self, duration: Duration, result: &Result<(), ReportError>) {
+        match result {
+            Ok(()) => self.success(duration),
+            Err(e) => self.failure(duration, &error_type(e.as_ref())),
+        }
+    }
+}
+
+/// Classify an error for self metrics.
+///
+/// IO errors in the source chain are named by their kind, like
+/// `connection_refused`, and sends skipped during backoff are `deferred`.
+/// Other errors are `rejected` if they are `Rejected`, or `other`.
+pub fn error_type(error: &(dyn Error + 'static)) -> String {
+    let mut rejected = false;
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if e.is::<Deferred>() {
+            return "deferred".to_owned();
+        }
+        if let Some(io_error) = e.downcast_ref::<io::Error>() {
+            return snake_case(&format!("{:?}", io_error.kind()));
+        }
+        rejected |= e.is::<Rejected>();
+        current = e.source();
+    }
+    if rejected { "rejected" } else { "other" }.to_owned()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.push(c.to_ascii_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Error of `RetryBuffer::send` called during backoff, when nothing is sent.
+#[derive(Debug)]
+pub struct Deferred {
+    /// Number of points waiting for delivery.
+    pub pending: usize,
+    /// Time until the next retry.
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for Deferred {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} points deferred for {:?} after failed delivery",
+            self.pending, self.retry_in
+        )
+    }
+}
+
+impl Error for Deferred {}
+
+/// Error of points rejected by the destination, like malformed ones, which
+/// would fail again on retries.
+///
+/// `RetryBuffer` drops batches failed with it, instead of blocking newer
+/// points behind them.
+#[derive(Debug)]
+pub struct Rejected(pub ReportError);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "rejected, {}", self.0)
+    }
+}
+
+impl Error for Rejected {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// A bounded buffer of points waiting for delivery, for push based reporters.
+///
+/// Points that failed to send are kept and retried, after a delay that grows
+/// exponentially with consecutive failures, so a short outage of the
+/// destination doesn't leave holes in the data. Points are sent in order,
+/// and the oldest ones are dropped when the buffer is full.
+pub struct RetryBuffer<T> {
+    options: RetryOptions,
+    points: VecDeque<T>,
+    // number of points at the front that failed before
+    attempted: usize,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    metrics: Option<DeliveryMetrics>,
+}
+
+impl<T> Debug for RetryBuffer<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("RetryBuffer")
+            .field("options", &self.options)
+            .field("len", &self.points.len())
+            .field("attempted", &self.attempted)
+            .field("retry_at", &self.retry_at)
+            .finish()
+    }
+}
+
+impl<T> RetryBuffer<T> {
+    pub fn new(options: RetryOptions) -> RetryBuffer<T> {
+        RetryBuffer {
+            backoff: options.initial_backoff,
+            options,
+            points: VecDeque::new(),
+            attempted: 0,
+            retry_at: None,
+            metrics: None,
+        }
+    }
+
+    /// Count delivered points with given metrics.
+    pub fn with_metrics(mut self, metrics: DeliveryMetrics) -> RetryBuffer<T> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Add points to the end of the buffer, dropping oldest points if it's
+    /// full.
+    pub fn push<I: IntoIterator<Item = T>>(&mut self, points: I) {
+        self.points.extend(points);
+
+        let overflow = self.points.len().saturating_sub(self.options.capacity);
+        if overflow > 0 {
+            self.points.drain(..overflow);
+            self.attempted = self.attempted.saturating_sub(overflow);
+            if let Some(metrics) = self.metrics.as_ref() {
+                metrics.dropped.inc(overflow as u64);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Retry on next `send` without waiting for backoff, like on the final
+    /// report.
+    pub fn retry_now(&mut self) {
+        self.retry_at = None;
+    }
+
+    /// Send buffered points in order, in batches of at most `batch_size`.
+    ///
+    /// Sending stops at the first failed batch, which is kept with the rest
+    /// and retried on a later call once the backoff has passed. Calls during
+    /// backoff send nothing and return a `Deferred` error. Batches failed
+    /// with `Rejected` are dropped instead, and the error is returned after
+    /// the rest are sent.
+    pub fn send<F>(&mut self, batch_size: usize, mut f: F) -> Result<(), ReportError>
+    where
+        F: FnMut(&[T]) -> Result<(), ReportError>,
+    {
+        if let Some(at) = self.retry_at {
+            let now = Instant::now();
+            if now < at && !self.points.is_empty() {
+                return Err(Box::new(Deferred {
+                    pending: self.points.len(),
+                    retry_in: at - now,
+                }));
+            }
+        }
+
+        let batch_size = batch_size.max(1);
+        let mut rejected = None;
+        while !self.points.is_empty() {
+            let n = batch_size.min(self.points.len());
+            let retried = n.min(self.attempted);
+            let result = f(&self.points.make_contiguous()[..n]);
+
+            if let Some(metrics) = self.metrics.as_ref() {
+                metrics.retried.inc(retried as u64);
+                if result.is_ok() {
+                    metrics.sent.inc(n as u64);
+                } else {
+                    metrics.failed.inc(n as u64);
+                }
+            }
+
+            if let Err(e) = result {
+                if e.is::<Rejected>() {
+                    self.points.drain(..n);
+                    self.attempted -= retried;
+                    if let Some(metrics) = self.metrics.as_ref() {
+                        metrics.dropped.inc(n as u64);
+                    }
+                    rejected = Some(e);
+                    continue;
+                }
+                self.attempted = self.attempted.max(n);
+                self.retry_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(self.options.max_backoff);
+                return Err(e);
+            }
+
+            self.points.drain(..n);
+            self.attempted -= retried;
+            self.backoff = self.options.initial_backoff;
+            self.retry_at = None;
+        }
+        rejected.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{
+        error_type, Deferred, DeliveryMetrics, Rejected, ReportError, Reporter, ReporterMetrics,
+        RetryBuffer, RetryOptions, ScheduledReporter, Snapshot,
+    };
+    use crate::key::{Key, Tag};
+    use crate::registry::MetricsRegistry;
+
+    #[derive(Default)]
+    struct StubReporter {
+        reports: Arc<Mutex<Vec<usize>>>,
+        final_reports: Arc<Mutex<usize>>,
+    }
+
+    impl Reporter for StubReporter {
+        fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+            let mut reports = self.reports.lock().unwrap();
+            reports.push(snapshot.len());
+            if reports.len() > 1 {
+                Err("stub error".into())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn report_final(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+            *self.final_reports.lock().unwrap() += 1;
+            self.report(snapshot)
+        }
+    }
+
+    #[test]
+    fn test_scheduled_reporter() {
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.counter("c").inc(1);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_ref = errors.clone();
+
+        let reporter = StubReporter {
+            reports: reports.clone(),
+            ..Default::default()
+        };
+        let handle = ScheduledReporter::new(registry.clone(), reporter, Duration::from_secs(3600))
+            .jitter(Duration::from_secs(1))
+            .on_error(move |e| errors_ref.lock().unwrap().push(e.to_string()))
+            .start();
+
+        // first report at start, then the flush and the final one on stop
+        handle.flush();
+        registry.counter("d").inc(1);
+        handle.stop();
+        handle.join();
+
+        assert_eq!(vec![1, 1, 2], *reports.lock().unwrap());
+        assert_eq!(2, errors.lock().unwrap().len());
+
+        // flushing a stopped reporter returns immediately
+        handle.flush();
+        futures_executor::block_on(handle.flush_async());
+    }
+
+    #[test]
+    fn test_final_report_on_drop() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let final_reports = Arc::new(Mutex::new(0));
+        let reporter = StubReporter {
+            reports: reports.clone(),
+            final_reports: final_reports.clone(),
+        };
+
+        let handle =
+            ScheduledReporter::new(registry.clone(), reporter, Duration::from_secs(3600)).start();
+        futures_executor::block_on(handle.flush_async());
+        registry.counter("c").inc(1);
+
+        let cloned = handle.clone();
+        drop(handle);
+        assert_eq!(2, reports.lock().unwrap().len());
+        assert_eq!(0, *final_reports.lock().unwrap());
+        drop(cloned);
+
+        assert_eq!(vec![0, 0, 1], *reports.lock().unwrap());
+        assert_eq!(1, *final_reports.lock().unwrap());
+    }
+
+    #[test]
+    fn test_retry_buffer() {
+        let registry = MetricsRegistry::new();
+        let metrics = DeliveryMetrics::new(&registry, "delivery");
+        let mut buffer = RetryBuffer::new(RetryOptions {
+            capacity: 4,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_secs(1),
+        })
+        .with_metrics(metrics.clone());
+
+        let mut sent = Vec::new();
+        buffer.push(vec![1, 2, 3]);
+        assert!(buffer.send(2, |_| Err("down".into())).is_err());
+        assert_eq!(3, buffer.len());
+
+        // full buffer drops the oldest, and nothing is sent during backoff
+        buffer.push(vec![4, 5]);
+        let deferred = buffer
+            .send(2, |batch| {
+                sent.extend_from_slice(batch);
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(deferred.is::<Deferred>());
+        assert_eq!("deferred", error_type(deferred.as_ref()));
+        assert!(sent.is_empty());
+
+        thread::sleep(Duration::from_millis(30));
+        buffer
+            .send(3, |batch| {
+                sent.extend_from_slice(batch);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec![2, 3, 4, 5], sent);
+        assert!(buffer.is_empty());
+
+        // backoff is skipped when asked to
+        buffer.push(vec![6]);
+        assert!(buffer.send(2, |_| Err("down".into())).is_err());
+        buffer.retry_now();
+        buffer
+            .send(2, |batch| {
+                sent.extend_from_slice(batch);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec![2, 3, 4, 5, 6], sent);
+
+        // rejected batches are dropped without blocking later ones
+        buffer.push(vec![7, 8, 9]);
+        let rejected = buffer
+            .send(2, |batch| {
+                if batch.contains(&7) {
+                    return Err(Box::new(Rejected("malformed".into())));
+                }
+                sent.extend_from_slice(batch);
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!("rejected", error_type(rejected.as_ref()));
+        assert_eq!(vec![2, 3, 4, 5, 6, 9], sent);
+        assert!(buffer.is_empty());
+
+        assert_eq!(6, metrics.sent());
+        assert_eq!(5, metrics.failed());
+        assert_eq!(2, metrics.retried());
+        assert_eq!(3, metrics.dropped());
+    }
+
+    #[test]
+    fn test_reporter_metrics() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let metrics = ReporterMetrics::new(registry.clone(), "reporter");
+
+        metrics.add_points(3);
+        metrics.add_bytes(120);
+        metrics.record(Duration::from_millis(5), &Ok(()));
+        let refused: ReportError =
+            io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        metrics.record(Duration::from_millis(5), &Err(refused));
+        metrics.failure(Duration::from_millis(5), "database");
+
+        assert_eq!(3, registry.timer("reporter.duration").rate().count());
+        assert_eq!(3, registry.monotonic_counter("reporter.points").value());
+        assert_eq!(120, registry.monotonic_counter("reporter.bytes").value());
+        let errors = |t| {
+            registry
+                .monotonic_counter_with_tags("reporter.errors", vec![Tag::new("type", t)])
+                .value()
+        };
+        assert_eq!(1, errors("connection_refused"));
+        assert_eq!(1, errors("database"));
+
+        let last_success = registry.snapshots()[&Key::from_name("reporter.last_success")]
+            .as_gauge()
+            .unwrap()
+            .value();
+        assert!(last_success > 0.0);
+
+        let other: ReportError = "stub error".into();
+        assert_eq!("other", error_type(other.as_ref()));
+    }
+}
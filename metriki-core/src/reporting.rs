@@ -0,0 +1,350 @@
+//! Common options for reporters and exporters.
+
+use std::collections::HashMap;
+
+use crate::key::Key;
+use crate::metrics::{
+    ApdexSnapshot, BucketedHistogramSnapshot, ExponentialHistogramSnapshot, HistogramSnapshot,
+    Meter,
+};
+
+/// Options on what data reporters and exporters send for each metric.
+///
+/// ```
+/// use metriki_core::reporting::{Rate, ReportingOptions};
+///
+/// let options = ReportingOptions {
+///     percentiles: vec![0.5, 0.99],
+///     rates: vec![Rate::M1],
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportingOptions {
+    /// Quantiles of histograms and timers to report, in range of `[0, 1]`.
+    pub percentiles: Vec<f64>,
+    /// Rates of meters and timers to report.
+    pub rates: Vec<Rate>,
+}
+
+impl Default for ReportingOptions {
+    fn default() -> ReportingOptions {
+        ReportingOptions {
+            percentiles: vec![0.5, 0.75, 0.9, 0.99, 0.999],
+            rates: vec![Rate::M1, Rate::M5, Rate::M15, Rate::Mean],
+        }
+    }
+}
+
+impl ReportingOptions {
+    /// Iterate configured percentiles with their names, like `("p99", 0.99)`.
+    pub fn named_percentiles(&self) -> impl Iterator<Item = (String, f64)> + '_ {
+        self.percentiles.iter().map(|q| (percentile_name(*q), *q))
+    }
+}
+
+/// Rates of a `Meter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    M1,
+    M5,
+    M15,
+    Mean,
+}
+
+impl Rate {
+    /// Short name of the rate: `m1`, `m5`, `m15` or `mean_rate`.
+    ///
+    /// The mean rate is not named `mean`, which is taken by the mean of
+    /// histograms, like latency of timers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rate::M1 => "m1",
+            Rate::M5 => "m5",
+            Rate::M15 => "m15",
+            Rate::Mean => "mean_rate",
+        }
+    }
+
+    /// Name of the rate with `_rate` suffix: `m1_rate`, `m5_rate`,
+    /// `m15_rate` or `mean_rate`.
+    pub fn rate_name(&self) -> &'static str {
+        match self {
+            Rate::M1 => "m1_rate",
+            Rate::M5 => "m5_rate",
+            Rate::M15 => "m15_rate",
+            Rate::Mean => "mean_rate",
+        }
+    }
+
+    /// Read this rate from the meter.
+    pub fn value(&self, meter: &Meter) -> f64 {
+        match self {
+            Rate::M1 => meter.m1_rate(),
+            Rate::M5 => meter.m5_rate(),
+            Rate::M15 => meter.m15_rate(),
+            Rate::Mean => meter.mean_rate(),
+        }
+    }
+}
+
+/// Whether counts are reported as totals since start, or as changes since
+/// last report.
+///
+/// Pull based exporters like Prometheus expect cumulative values, while push
+/// protocols like statsd add up what they receive, so they need deltas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Temporality {
+    #[default]
+    Cumulative,
+    Delta,
+}
+
+/// Values last reported by a reporter, to read counts in its temporality.
+///
+/// Each reporter keeps its own state, so reporters with different
+/// temporality can report the same registry.
+///
+/// Counts of meters, timers, monotonic counters, apdex, bucketed and
+/// exponential histograms are cumulative in the registry, they are turned into deltas
+/// against the last reported value.
+/// Histogram snapshots are reset on read, so their count and sum are
+/// accumulated for cumulative reporters. Quantiles are always calculated
+/// from samples since last snapshot.
+///
+/// ```
+/// use metriki_core::key::Key;
+/// use metriki_core::reporting::{Temporality, TemporalityState};
+///
+/// let mut state = TemporalityState::new(Temporality::Delta);
+/// let key = Key::from_name("requests");
+///
+/// assert_eq!(10, state.count(&key, 10));
+/// assert_eq!(5, state.count(&key, 15));
+/// ```
+#[derive(Debug)]
+pub struct TemporalityState {
+    temporality: Temporality,
+    counts: HashMap<Key, u64>,
+    histograms: HashMap<Key, (u64, u64)>,
+    buckets: HashMap<Key, BucketedHistogramSnapshot>,
+    exponentials: HashMap<Key, ExponentialHistogramSnapshot>,
+    apdexes: HashMap<Key, ApdexSnapshot>,
+}
+
+impl TemporalityState {
+    pub fn new(temporality: Temporality) -> TemporalityState {
+        TemporalityState {
+            temporality,
+            counts: HashMap::new(),
+            histograms: HashMap::new(),
+            buckets: HashMap::new(),
+            exponentials: HashMap::new(),
+            apdexes: HashMap::new(),
+        }
+    }
+
+    pub fn temporality(&self) -> Temporality {
+        self.temporality
+    }
+
+    /// Read a cumulative count, like `Meter::count` or `MonotonicCounter::value`.
+    ///
+    /// If the count is lower than last reported, the metric is considered
+    /// reset and the whole count is returned as delta.
+    pub fn count(&mut self, key: &Key, count: u64) -> u64 {
+        match self.temporality {
+            Temporality::Cumulative => count,
+            Temporality::Delta => {
+                let last = self.counts.insert(key.clone(), count).unwrap_or(0);
+                if count >= last {
+                    count - last
+                } else {
+                    count
+                }
+            }
+        }
+    }
+
+    /// Read bucket counts of a bucketed histogram snapshot, which is cumulative.
+    pub fn buckets(
+        &mut self,
+        key: &Key,
+        snapshot: BucketedHistogramSnapshot,
+    ) -> BucketedHistogramSnapshot {
+        match self.temporality {
+            Temporality::Cumulative => snapshot,
+            Temporality::Delta => {
+                let delta = match self.buckets.get(key) {
+                    Some(last) => snapshot.delta(last),
+                    None => snapshot.clone(),
+                };
+                self.buckets.insert(key.clone(), snapshot);
+                delta
+            }
+        }
+    }
+
+    /// Read an exponential histogram snapshot, which is cumulative.
+    pub fn exponential(
+        &mut self,
+        key: &Key,
+        snapshot: ExponentialHistogramSnapshot,
+    ) -> ExponentialHistogramSnapshot {
+        match self.temporality {
+            Temporality::Cumulative => snapshot,
+            Temporality::Delta => {
+                let delta = match self.exponentials.get(key) {
+                    Some(last) => snapshot.delta(last),
+                    None => snapshot.clone(),
+                };
+                self.exponentials.insert(key.clone(), snapshot);
+                delta
+            }
+        }
+    }
+
+    /// Read counts of an apdex snapshot, which is cumulative.
+    ///
+    /// With delta temporality, the score is calculated from samples since
+    /// last report.
+    pub fn apdex(&mut self, key: &Key, snapshot: ApdexSnapshot) -> ApdexSnapshot {
+        match self.temporality {
+            Temporality::Cumulative => snapshot,
+            Temporality::Delta => {
+                let delta = match self.apdexes.get(key) {
+                    Some(last) => snapshot.delta(last),
+                    None => snapshot,
+                };
+                self.apdexes.insert(key.clone(), snapshot);
+                delta
+            }
+        }
+    }
+
+    /// Read count and sum of a histogram snapshot.
+    pub fn histogram(&mut self, key: &Key, snapshot: &HistogramSnapshot) -> (u64, u64) {
+        match self.temporality {
+            Temporality::Delta => (snapshot.count(), snapshot.sum()),
+            Temporality::Cumulative => {
+                let (count, sum) = self.histograms.entry(key.clone()).or_insert((0, 0));
+                *count = count.saturating_add(snapshot.count());
+                *sum = sum.saturating_add(snapshot.sum());
+                (*count, *sum)
+            }
+        }
+    }
+}
+
+/// Name of a quantile as percentile: `0.5` as `p50` and `0.999` as `p999`.
+pub fn percentile_name(quantile: f64) -> String {
+    if quantile >= 1.0 {
+        return "p100".to_owned();
+    }
+
+    let formatted = format!("{}", quantile);
+    let mut digits = formatted.trim_start_matches("0.").to_owned();
+    if digits.len() == 1 {
+        digits.push('0');
+    }
+    format!("p{}", digits)
+}
+
+/// Replace characters not allowed in Prometheus metric names, like dots in
+/// `metriki.prometheus.scrape.duration`, with `_`.
+///
+/// Colons, used by recording rules, are kept.
+pub fn prometheus_metric_name(name: &str) -> String {
+    sanitize_prometheus_name(name, true)
+}
+
+/// Replace characters not allowed in Prometheus label names with `_`.
+pub fn prometheus_label_name(name: &str) -> String {
+    sanitize_prometheus_name(name, false)
+}
+
+fn sanitize_prometheus_name(name: &str, metric_name: bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || (metric_name && c == ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        percentile_name, prometheus_label_name, prometheus_metric_name, Rate, Temporality,
+        TemporalityState,
+    };
+    use crate::key::Key;
+    use crate::metrics::Metric;
+
+    #[test]
+    fn test_percentile_name() {
+        assert_eq!("p50", percentile_name(0.5));
+        assert_eq!("p75", percentile_name(0.75));
+        assert_eq!("p90", percentile_name(0.9));
+        assert_eq!("p99", percentile_name(0.99));
+        assert_eq!("p999", percentile_name(0.999));
+        assert_eq!("p05", percentile_name(0.05));
+        assert_eq!("p100", percentile_name(1.0));
+    }
+
+    #[test]
+    fn test_prometheus_names() {
+        assert_eq!(
+            "metriki_prometheus_scrape",
+            prometheus_metric_name("metriki.prometheus.scrape")
+        );
+        assert_eq!("http:requests", prometheus_metric_name("http:requests"));
+        assert_eq!("_2xx", prometheus_metric_name("2xx"));
+        assert_eq!("span_kind", prometheus_label_name("span:kind"));
+    }
+
+    #[test]
+    fn test_rate_names() {
+        let rates = [Rate::M1, Rate::M5, Rate::M15, Rate::Mean];
+        assert_eq!(
+            vec!["m1", "m5", "m15", "mean_rate"],
+            rates.iter().map(Rate::name).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["m1_rate", "m5_rate", "m15_rate", "mean_rate"],
+            rates.iter().map(Rate::rate_name).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_temporality_state() {
+        let key = Key::from_name("latency");
+        let histogram = Metric::histogram();
+        let mut delta = TemporalityState::new(Temporality::Delta);
+        let mut cumulative = TemporalityState::new(Temporality::Cumulative);
+
+        assert_eq!(3, delta.count(&key, 3));
+        assert_eq!(0, delta.count(&key, 3));
+        assert_eq!(2, delta.count(&key, 5));
+        assert_eq!(1, delta.count(&key, 1));
+        assert_eq!(5, cumulative.count(&key, 5));
+
+        histogram.update(10);
+        histogram.update(20);
+        let snapshot = histogram.snapshot();
+        assert_eq!((2, 30), delta.histogram(&key, &snapshot));
+        assert_eq!((2, 30), cumulative.histogram(&key, &snapshot));
+
+        histogram.update(10);
+        let snapshot = histogram.snapshot();
+        assert_eq!((1, 10), delta.histogram(&key, &snapshot));
+        assert_eq!((3, 40), cumulative.histogram(&key, &snapshot));
+    }
+}
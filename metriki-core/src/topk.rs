@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::key::{Key, Tag};
+use crate::metrics::Metric;
+use crate::mset::MetricsSet;
+
+/// A `MetricsSet` that tracks the heaviest items of a stream, like top
+/// endpoints or tenants, in fixed memory.
+///
+/// It implements the Space-Saving algorithm: up to `capacity` items are
+/// counted, and when a new item comes to a full table, it takes the place
+/// of the least counted one and inherits its count. The count of an item
+/// may be overestimated by at most the count it inherited, while any item
+/// that occurs more than `total / capacity` times is guaranteed to be kept.
+///
+/// The top `n` items are reported as gauges named `name`, tagged with `tag`
+/// as the item. They are not counters because the count of an item starts
+/// over when it's evicted and comes back, or on `clear`.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use metriki_core::{MetricsRegistry, TopK};
+///
+/// let registry = MetricsRegistry::new();
+/// let top = Arc::new(TopK::new("requests.top", "endpoint", 10));
+/// registry.register_metrics_set("requests.top", top.clone());
+///
+/// top.observe("/index");
+/// top.observe_n("/login", 2);
+///
+/// assert_eq!(vec![("/login".to_owned(), 2), ("/index".to_owned(), 1)], top.top());
+/// ```
+#[derive(Debug)]
+pub struct TopK {
+    name: String,
+    tag: String,
+    n: usize,
+    capacity: usize,
+    items: Mutex<HashMap<String, Slot>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    count: u64,
+    error: u64,
+}
+
+impl TopK {
+    /// Create a top k set reporting `n` items, with capacity of `10 * n` items.
+    pub fn new(name: &str, tag: &str, n: usize) -> TopK {
+        TopK::with_capacity(name, tag, n, n.saturating_mul(10))
+    }
+
+    /// Create a top k set reporting `n` items, counting at most `capacity`
+    /// items. Larger capacity gives more accurate counts.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `capacity` is less than `n`.
+    pub fn with_capacity(name: &str, tag: &str, n: usize, capacity: usize) -> TopK {
+        assert!(capacity >= n, "Capacity must not be less than n.");
+
+        TopK {
+            name: name.to_owned(),
+            tag: tag.to_owned(),
+            n,
+            capacity,
+            items: Mutex::new(HashMap::with_capacity(capacity)),
+        }
+    }
+
+    /// Count an occurrence of the item.
+    pub fn observe(&self, item: &str) {
+        self.observe_n(item, 1);
+    }
+
+    /// Count `count` occurrences of the item.
+    pub fn observe_n(&self, item: &str, count: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut items = self.items.lock().unwrap();
+        if let Some(slot) = items.get_mut(item) {
+            slot.count = slot.count.saturating_add(count);
+            return;
+        }
+
+        let min = if items.len() < self.capacity {
+            0
+        } else {
+            // replace the least counted item
+            let (evicted, min) = items
+                .iter()
+                .min_by_key(|(_, slot)| slot.count)
+                .map(|(k, slot)| (k.clone(), slot.count))
+                .unwrap();
+            items.remove(&evicted);
+            min
+        };
+
+        items.insert(
+            item.to_owned(),
+            Slot {
+                count: min.saturating_add(count),
+                error: min,
+            },
+        );
+    }
+
+    /// Returns the top `n` items and their estimated counts, in descending
+    /// order of counts.
+    pub fn top(&self) -> Vec<(String, u64)> {
+        self.top_with_error()
+            .into_iter()
+            .map(|(item, count, _)| (item, count))
+            .collect()
+    }
+
+    /// Returns the top `n` items with their estimated counts and max
+    /// overestimation of the counts.
+    pub fn top_with_error(&self) -> Vec<(String, u64, u64)> {
+        let mut top: Vec<(String, u64, u64)> = self
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(item, slot)| (item.clone(), slot.count, slot.error))
+            .collect();
+
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(self.n);
+        top
+    }
+
+    /// Forget all counted items.
+    pub fn clear(&self) {
+        self.items.lock().unwrap().clear();
+    }
+
+    fn gauge(count: u64) -> Metric {
+        Metric::gauge(Box::new(move || count as f64)).into()
+    }
+}
+
+impl MetricsSet for TopK {
+    /// Top items without tags, named as `name.item`.
+    fn get_all(&self) -> HashMap<String, Metric> {
+        self.top()
+            .into_iter()
+            .map(|(item, count)| (format!("{}.{}", self.name, item), TopK::gauge(count)))
+            .collect()
+    }
+
+    fn get_all_with_tags(&self) -> HashMap<Key, Metric> {
+        self.top()
+            .into_iter()
+            .map(|(item, count)| {
+                let key = Key::from(&self.name, vec![Tag::new(&self.tag, &item)]);
+                (key, TopK::gauge(count))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::TopK;
+    use crate::key::{Key, Tag};
+    use crate::registry::MetricsRegistry;
+
+    #[test]
+    fn test_top_k() {
+        let top = TopK::with_capacity("top", "item", 2, 3);
+
+        top.observe_n("a", 10);
+        top.observe_n("b", 5);
+        top.observe_n("c", 1);
+        // evicts c and inherits its count
+        top.observe_n("d", 2);
+        top.observe("b");
+
+        assert_eq!(
+            vec![("a".to_owned(), 10, 0), ("b".to_owned(), 6, 0)],
+            top.top_with_error()
+        );
+
+        top.observe_n("d", 10);
+        assert_eq!(vec![("d".to_owned(), 13), ("a".to_owned(), 10)], top.top());
+    }
+
+    #[test]
+    fn test_top_k_metrics_set() {
+        let registry = MetricsRegistry::new();
+        let top = Arc::new(TopK::new("requests.top", "endpoint", 1));
+        registry.register_metrics_set("top", top.clone());
+
+        top.observe("/index");
+        top.observe("/index");
+        top.observe("/login");
+
+        let snapshots = registry.snapshots();
+        assert_eq!(1, snapshots.len());
+
+        let key = Key::from("requests.top", vec![Tag::new("endpoint", "/index")]);
+        let gauge = snapshots.get(&key).and_then(|m| m.as_gauge()).unwrap();
+        assert_eq!(2.0, gauge.value());
+
+        // the count starts over after clear, not a counter reset
+        top.clear();
+        top.observe("/index");
+        let snapshots = registry.snapshots();
+        let gauge = snapshots.get(&key).and_then(|m| m.as_gauge()).unwrap();
+        assert_eq!(1.0, gauge.value());
+    }
+}
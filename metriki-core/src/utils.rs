@@ -1,3 +1,17 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 pub(crate) fn secs_to_nanos(s: u64) -> u64 {
     s * 1000000000
 }
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // threads are assigned to stripes in round-robin
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Index of stripe for current thread, to be used with striped metrics.
+pub(crate) fn thread_stripe() -> usize {
+    STRIPE.with(|s| *s)
+}
@@ -1,15 +1,23 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use derive_builder::Builder;
-use influxdb::{Client, InfluxDbWriteable, Timestamp, WriteQuery};
+use influxdb::{Error as InfluxDbError, InfluxDbWriteable, Query, Timestamp, WriteQuery};
 
 use log::warn;
 use metriki_core::key::Key;
 use metriki_core::metrics::*;
+use metriki_core::reporter::{
+    error_type, DeliveryMetrics, Rejected, ReportError, Reporter, ReporterHandle, ReporterMetrics,
+    RetryBuffer, RetryOptions, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
 use metriki_core::MetricsRegistry;
-use tokio::time::{sleep, Duration};
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
 
 #[derive(Builder, Debug)]
 pub struct InfluxDbReporter {
@@ -30,8 +38,47 @@ pub struct InfluxDbReporter {
     tags: HashMap<String, String>,
     #[builder(default = "50")]
     batch_size: usize,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Whether counts are written as totals or changes since last report.
+    #[builder(default)]
+    temporality: Temporality,
+    /// Buffering and backoff of points failed to write.
+    #[builder(default)]
+    retry_options: RetryOptions,
+    /// Registry to record metrics of the reporter itself in. It can be the
+    /// reported registry itself.
+    ///
+    /// Reports are recorded as `ReporterMetrics` prefixed with
+    /// `metriki.influxdb.report`, and delivered points are counted by
+    /// monotonic counters `metriki.influxdb.points.{sent,failed,retried,dropped}`.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    buffer: Option<RetryBuffer<WriteQuery>>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+    #[builder(setter(skip))]
+    client: Option<Client>,
 }
 
+/// Error response of a write, with its status code.
+#[derive(Debug)]
+struct WriteError {
+    status: StatusCode,
+    body: String,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "influxdb responded {}, {}", self.status, self.body)
+    }
+}
+
+impl Error for WriteError {}
+
 fn system_time_millis() -> u128 {
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH);
     timestamp
@@ -40,46 +87,49 @@ fn system_time_millis() -> u128 {
 }
 
 impl InfluxDbReporter {
-    fn new_client(&self) -> Client {
-        let client = Client::new(&self.url, &self.database);
-
-        if let (Some(username), Some(password)) = (self.username.as_ref(), self.password.as_ref()) {
-            client.with_auth(username, password)
-        } else {
-            client
-        }
+    /// Start reporting in a background thread.
+    ///
+    /// The reporter is stopped after a final report when the returned handle
+    /// is dropped, or `stop` is called.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval)
+            .on_error(|e| warn!("Failed to write influxdb, {}", e))
+            .start()
     }
 
-    pub fn start(self) {
-        let looper = move || {
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("can not create tokio runtime");
-            runtime.block_on(async {
-                loop {
-                    let metrics = self.registry.snapshots();
-                    let client = self.new_client();
-                    let queries: Vec<WriteQuery> = metrics
-                        .iter()
-                        .map(|(key, metric)| match metric {
-                            Metric::Counter(c) => self.report_counter(key, c.as_ref()),
-                            Metric::Gauge(g) => self.report_gauge(key, g.as_ref()),
-                            Metric::Timer(t) => self.report_timer(key, t.as_ref()),
-                            Metric::Meter(m) => self.report_meter(key, m.as_ref()),
-                            Metric::Histogram(h) => self.report_histogram(key, &h.snapshot()),
-                        })
-                        .collect();
-
-                    if !queries.is_empty() {
-                        self.do_query(&client, queries).await;
-                    }
-
-                    sleep(Duration::from_secs(self.interval_secs)).await;
+    fn report_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        state: &mut TemporalityState,
+    ) -> Vec<WriteQuery> {
+        snapshot
+            .iter()
+            .map(|(key, metric)| match metric {
+                Metric::Counter(c) => self.report_counter(key, c.as_ref()),
+                Metric::MonotonicCounter(c) => {
+                    self.report_monotonic_counter(key, c.as_ref(), state)
+                }
+                Metric::Gauge(g) => self.report_gauge(key, g.as_ref()),
+                Metric::UniqueCounter(c) => self.with_key(key).add_field("value", c.value()),
+                Metric::Apdex(a) => {
+                    let snapshot = state.apdex(key, a.snapshot());
+                    self.with_apdex(self.with_key(key), &snapshot)
+                }
+                Metric::ExponentialHistogram(h) => {
+                    let snapshot = state.exponential(key, h.snapshot());
+                    self.report_exponential_histogram(key, &snapshot)
                 }
+                Metric::BucketedHistogram(h) => {
+                    let snapshot = state.buckets(key, h.snapshot());
+                    self.report_bucketed_histogram(key, &snapshot)
+                }
+                Metric::Timer(t) => self.report_timer(key, t.as_ref(), state),
+                Metric::Meter(m) => self.report_meter(key, m.as_ref(), state),
+                Metric::Histogram(h) => self.report_histogram(key, &h.snapshot(), state),
             })
-        };
-        std::thread::spawn(looper);
+            .collect()
     }
 
     #[inline]
@@ -99,62 +149,173 @@ impl InfluxDbReporter {
         query
     }
 
-    #[inline]
-    async fn do_query(&self, client: &Client, query: Vec<WriteQuery>) {
-        // send query by chunk to avoid influxdb max request entity
-        // error
-        let chunks = query.chunks(self.batch_size);
-        for ch in chunks {
-            let batch = ch.to_owned();
-            if let Err(e) = client.query(batch).await {
-                warn!("Failed to write influxdb, {}", e)
-            }
+    fn new_buffer(&self) -> RetryBuffer<WriteQuery> {
+        let buffer = RetryBuffer::new(self.retry_options.clone());
+        if let Some(registry) = self.self_metrics.as_ref() {
+            buffer.with_metrics(DeliveryMetrics::new(registry, "metriki.influxdb.points"))
+        } else {
+            buffer
         }
     }
 
-    fn report_meter(&self, key: &Key, meter: &Meter) -> WriteQuery {
-        self.with_key(key)
-            .add_field("m1", meter.m1_rate())
-            .add_field("m5", meter.m5_rate())
-            .add_field("m15", meter.m15_rate())
+    fn send_buffer(&mut self, buffer: &mut RetryBuffer<WriteQuery>) -> Result<(), ReportError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let client = self.client.get_or_insert_with(Client::new);
+        let url = format!("{}/write", self.url);
+        let mut params = vec![("db", self.database.as_str())];
+        if let (Some(username), Some(password)) = (self.username.as_ref(), self.password.as_ref()) {
+            params.push(("u", username));
+            params.push(("p", password));
+        }
+
+        let metrics = self.metrics.as_ref();
+
+        // send query by chunk to avoid influxdb max request entity error
+        buffer.send(self.batch_size, |batch| {
+            let precision = batch[0].get_precision();
+            // points that can't be encoded won't be on retries either
+            let body = batch
+                .to_vec()
+                .build()
+                .map_err(|e| Rejected(e.into()))?
+                .get();
+            let bytes = body.len();
+
+            let response = client
+                .post(&url)
+                .query(&params)
+                .query(&[("precision", precision)])
+                .body(body)
+                .send()?;
+            let status = response.status();
+            if !status.is_success() {
+                let error = WriteError {
+                    status,
+                    body: response.text().unwrap_or_default(),
+                };
+                // client errors, like malformed points, fail again on retries
+                return Err(if is_retryable(status) {
+                    Box::new(error)
+                } else {
+                    Box::new(Rejected(Box::new(error)))
+                });
+            }
+
+            if let Some(metrics) = metrics {
+                metrics.add_bytes(bytes as u64);
+            }
+            Ok(())
+        })
+    }
+
+    fn report_meter(&self, key: &Key, meter: &Meter, state: &mut TemporalityState) -> WriteQuery {
+        self.with_rates(self.with_key(key), meter)
+            .add_field("count", state.count(key, meter.count()))
     }
+
     fn report_gauge(&self, key: &Key, gauge: &Gauge) -> WriteQuery {
         let value = gauge.value();
         self.with_key(key).add_field("value", value)
     }
 
-    fn report_histogram(&self, key: &Key, snapshot: &HistogramSnapshot) -> WriteQuery {
-        self.with_key(key)
-            .add_field("p50", snapshot.quantile(0.5))
-            .add_field("p75", snapshot.quantile(0.75))
-            .add_field("p90", snapshot.quantile(0.90))
-            .add_field("p99", snapshot.quantile(0.99))
-            .add_field("p999", snapshot.quantile(0.999))
-            .add_field("min", snapshot.min())
-            .add_field("max", snapshot.max())
-            .add_field("mean", snapshot.mean())
+    fn report_histogram(
+        &self,
+        key: &Key,
+        snapshot: &HistogramSnapshot,
+        state: &mut TemporalityState,
+    ) -> WriteQuery {
+        let totals = state.histogram(key, snapshot);
+        self.with_histogram(self.with_key(key), snapshot, totals)
+    }
+
+    fn report_bucketed_histogram(
+        &self,
+        key: &Key,
+        snapshot: &BucketedHistogramSnapshot,
+    ) -> WriteQuery {
+        let mut wq = self.with_key(key);
+        for (le, count) in snapshot.iter_cumulative() {
+            wq = wq.add_field(format!("le_{}", le), count);
+        }
+        wq.add_field("count", snapshot.count())
+            .add_field("sum", snapshot.sum())
+    }
+
+    fn report_exponential_histogram(
+        &self,
+        key: &Key,
+        snapshot: &ExponentialHistogramSnapshot,
+    ) -> WriteQuery {
+        let mut wq = self.with_key(key);
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            wq = wq.add_field(pname, snapshot.quantile(q));
+        }
+        wq.add_field("count", snapshot.count())
+            .add_field("sum", snapshot.sum())
     }
 
     fn report_counter(&self, key: &Key, c: &Counter) -> WriteQuery {
         self.with_key(key).add_field("value", c.value())
     }
 
-    fn report_timer(&self, key: &Key, t: &Timer) -> WriteQuery {
+    fn report_monotonic_counter(
+        &self,
+        key: &Key,
+        c: &MonotonicCounter,
+        state: &mut TemporalityState,
+    ) -> WriteQuery {
+        self.with_key(key)
+            .add_field("value", state.count(key, c.value()))
+    }
+
+    fn report_timer(&self, key: &Key, t: &Timer, state: &mut TemporalityState) -> WriteQuery {
         let rate = t.rate();
         let latency = t.latency();
+        let totals = state.histogram(key, &latency);
 
-        self.with_key(key)
-            .add_field("p50", latency.quantile(0.5))
-            .add_field("p75", latency.quantile(0.75))
-            .add_field("p90", latency.quantile(0.90))
-            .add_field("p99", latency.quantile(0.99))
-            .add_field("p999", latency.quantile(0.999))
-            .add_field("min", latency.min())
-            .add_field("max", latency.max())
-            .add_field("mean", latency.mean())
-            .add_field("m1", rate.m1_rate())
-            .add_field("m5", rate.m5_rate())
-            .add_field("m15", rate.m15_rate())
+        let wq = self.with_rates(
+            self.with_histogram(self.with_key(key), &latency, totals),
+            rate,
+        );
+        if let Some(apdex) = t.apdex() {
+            self.with_apdex(wq, &state.apdex(key, apdex.snapshot()))
+        } else {
+            wq
+        }
+    }
+
+    fn with_apdex(&self, wq: WriteQuery, snapshot: &ApdexSnapshot) -> WriteQuery {
+        wq.add_field("apdex", snapshot.score())
+            .add_field("satisfied", snapshot.satisfied())
+            .add_field("tolerating", snapshot.tolerating())
+            .add_field("frustrated", snapshot.frustrated())
+    }
+
+    fn with_rates(&self, mut wq: WriteQuery, meter: &Meter) -> WriteQuery {
+        for rate in self.reporting_options.rates.iter() {
+            wq = wq.add_field(rate.name(), rate.value(meter));
+        }
+        wq
+    }
+
+    fn with_histogram(
+        &self,
+        mut wq: WriteQuery,
+        snapshot: &HistogramSnapshot,
+        totals: (u64, u64),
+    ) -> WriteQuery {
+        let (count, sum) = totals;
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            wq = wq.add_field(pname, snapshot.quantile(q));
+        }
+        wq.add_field("min", snapshot.min())
+            .add_field("max", snapshot.max())
+            .add_field("mean", snapshot.mean())
+            .add_field("count", count)
+            .add_field("sum", sum)
     }
 
     fn with_key(&self, key: &Key) -> WriteQuery {
@@ -165,3 +326,261 @@ impl InfluxDbReporter {
         wq
     }
 }
+
+impl Reporter for InfluxDbReporter {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.influxdb.report"));
+        }
+        let start = Instant::now();
+
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(self.temporality));
+        let queries = self.report_snapshot(snapshot, &mut state);
+        self.state = Some(state);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.add_points(queries.len() as u64);
+        }
+
+        let mut buffer = self.buffer.take().unwrap_or_else(|| self.new_buffer());
+        buffer.push(queries);
+        let result = self.send_buffer(&mut buffer);
+        self.buffer = Some(buffer);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            match &result {
+                Ok(()) => metrics.success(start.elapsed()),
+                Err(e) => metrics.failure(start.elapsed(), &influxdb_error_type(e)),
+            }
+        }
+        result
+    }
+
+    fn report_final(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        // no later report to wait for backoff
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer.retry_now();
+        }
+        self.report(snapshot)
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn influxdb_error_type(error: &ReportError) -> String {
+    let error = match error.downcast_ref::<Rejected>() {
+        Some(Rejected(e)) => e,
+        None => error,
+    };
+    if let Some(e) = error.downcast_ref::<WriteError>() {
+        return match e.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "auth".to_owned(),
+            _ => "database".to_owned(),
+        };
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        if e.is_connect() || e.is_timeout() {
+            return "connection".to_owned();
+        }
+    }
+    match error.downcast_ref::<InfluxDbError>() {
+        Some(InfluxDbError::ConnectionError { .. }) => "connection".to_owned(),
+        Some(InfluxDbError::DatabaseError { .. }) => "database".to_owned(),
+        Some(InfluxDbError::AuthenticationError) | Some(InfluxDbError::AuthorizationError) => {
+            "auth".to_owned()
+        }
+        Some(_) => "protocol".to_owned(),
+        None => error_type(error.as_ref()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use metriki_core::key::Tag;
+    use metriki_core::reporter::{Reporter, RetryOptions};
+    use metriki_core::MetricsRegistry;
+
+    use super::InfluxDbReporterBuilder;
+
+    /// Start an http server that fails first writes with `failures` status
+    /// codes and accepts the rest, and returns its url and bodies of
+    /// received requests.
+    fn stub_influxdb(failures: &'static [u16]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let bodies_ref = bodies.clone();
+
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                assert!(request_line.starts_with("POST /write?db=metrics&precision=ms "));
+
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies_ref
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(body).unwrap());
+
+                let resp = if let Some(status) = failures.get(i) {
+                    let error = r#"{"error":"failed"}"#;
+                    format!(
+                        "HTTP/1.1 {} Error\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        error.len(),
+                        error
+                    )
+                } else {
+                    "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_owned()
+                };
+                stream.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+
+        (url, bodies)
+    }
+
+    #[test]
+    fn test_final_report_on_stop() {
+        let (url, bodies) = stub_influxdb(&[]);
+        let registry = Arc::new(MetricsRegistry::new());
+
+        let handle = InfluxDbReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .database("metrics")
+            .interval_secs(3600)
+            .build()
+            .unwrap()
+            .start();
+
+        // nothing to write yet
+        handle.flush();
+        assert!(bodies.lock().unwrap().is_empty());
+
+        registry.counter("requests").inc(3);
+        handle.stop();
+        handle.join();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(1, bodies.len());
+        assert!(bodies[0].starts_with("requests value=3i "));
+    }
+
+    #[test]
+    fn test_retry_failed_writes() {
+        let (url, bodies) = stub_influxdb(&[500]);
+        let registry = Arc::new(MetricsRegistry::new());
+        let self_metrics = Arc::new(MetricsRegistry::new());
+
+        let mut reporter = InfluxDbReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .database("metrics")
+            .retry_options(RetryOptions {
+                capacity: 10,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_secs(1),
+            })
+            .self_metrics(self_metrics.clone())
+            .build()
+            .unwrap();
+
+        registry.counter("requests").inc(3);
+        assert!(reporter.report(&registry.snapshots()).is_err());
+
+        thread::sleep(Duration::from_millis(20));
+        reporter.report(&registry.snapshots()).unwrap();
+
+        // the failed point is written again before the new one
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(2, bodies.len());
+        assert_eq!(2, bodies[1].lines().count());
+
+        let value = |name| self_metrics.monotonic_counter(name).value();
+        assert_eq!(2, value("metriki.influxdb.points.sent"));
+        assert_eq!(1, value("metriki.influxdb.points.failed"));
+        assert_eq!(1, value("metriki.influxdb.points.retried"));
+        assert_eq!(0, value("metriki.influxdb.points.dropped"));
+
+        // 1 point in first report, and 1 more in the second
+        assert_eq!(2, value("metriki.influxdb.report.points"));
+        assert!(value("metriki.influxdb.report.bytes") > 0);
+        let errors = self_metrics
+            .monotonic_counter_with_tags(
+                "metriki.influxdb.report.errors",
+                vec![Tag::new("type", "database")],
+            )
+            .value();
+        assert_eq!(1, errors);
+    }
+
+    #[test]
+    fn test_drop_rejected_writes() {
+        let (url, bodies) = stub_influxdb(&[400]);
+        let registry = Arc::new(MetricsRegistry::new());
+        let self_metrics = Arc::new(MetricsRegistry::new());
+
+        let mut reporter = InfluxDbReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .database("metrics")
+            .self_metrics(self_metrics.clone())
+            .build()
+            .unwrap();
+
+        registry.counter("requests").inc(3);
+        assert!(reporter.report(&registry.snapshots()).is_err());
+
+        // the rejected point is not written again, nor blocks new ones
+        reporter.report(&registry.snapshots()).unwrap();
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(2, bodies.len());
+        assert_eq!(1, bodies[1].lines().count());
+
+        let value = |name| self_metrics.monotonic_counter(name).value();
+        assert_eq!(1, value("metriki.influxdb.points.sent"));
+        assert_eq!(1, value("metriki.influxdb.points.failed"));
+        assert_eq!(0, value("metriki.influxdb.points.retried"));
+        assert_eq!(1, value("metriki.influxdb.points.dropped"));
+        let errors = self_metrics
+            .monotonic_counter_with_tags(
+                "metriki.influxdb.report.errors",
+                vec![Tag::new("type", "database")],
+            )
+            .value();
+        assert_eq!(1, errors);
+    }
+}
@@ -16,7 +16,8 @@ fn main() {
         Arc::new(JemallocMetricsSet::new("example.memory")),
     );
 
-    LogReporterBuilder::default()
+    // the reporter is stopped when the handle is dropped
+    let _reporter = LogReporterBuilder::default()
         .registry(global_registry())
         .interval_secs(5)
         .build()
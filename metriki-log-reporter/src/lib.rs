@@ -1,10 +1,13 @@
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use derive_builder::Builder;
 use log::{log, Level};
 use metriki_core::metrics::*;
+use metriki_core::reporter::{
+    ReportError, Reporter, ReporterHandle, ReporterMetrics, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
 use metriki_core::MetricsRegistry;
 
 #[derive(Builder, Debug)]
@@ -16,105 +19,260 @@ pub struct LogReporter {
     interval_secs: u64,
     #[builder(default = "Level::Info")]
     level: Level,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Whether counts are logged as totals or changes since last report.
+    #[builder(default)]
+    temporality: Temporality,
+    /// Registry to record metrics of the reporter itself in, as
+    /// `ReporterMetrics` prefixed with `metriki.log.report`. Each line logged
+    /// is counted as a point, and bytes are not counted.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+}
+
+/// Log a line of the report, counted as a point in self metrics.
+macro_rules! report {
+    ($reporter:expr, $($arg:tt)+) => {{
+        if let Some(metrics) = $reporter.metrics.as_ref() {
+            metrics.add_points(1);
+        }
+        log!($reporter.level, $($arg)+)
+    }};
 }
 
 impl LogReporter {
-    pub fn start(self) {
-        let looper = move || loop {
-            let metrics = self.registry.snapshots();
-            for (ref key, metric) in metrics {
-                match metric {
-                    Metric::Counter(c) => self.report_counter(key.key(), c.as_ref()),
-                    Metric::Gauge(g) => self.report_gauge(key.key(), g.as_ref()),
-                    Metric::Timer(t) => self.report_timer(key.key(), t.as_ref()),
-                    Metric::Meter(m) => self.report_meter(key.key(), m.as_ref()),
-                    Metric::Histogram(h) => self.report_histogram(key.key(), &h.snapshot()),
+    /// Start reporting in a background thread.
+    ///
+    /// The reporter is stopped after a final report when the returned handle
+    /// is dropped, or `stop` is called.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval).start()
+    }
+
+    fn report_snapshot(&self, snapshot: &Snapshot, state: &mut TemporalityState) {
+        for (key, metric) in snapshot {
+            match metric {
+                Metric::Counter(c) => self.report_counter(key.key(), c.as_ref()),
+                Metric::MonotonicCounter(c) => {
+                    self.report_count(key.key(), state.count(key, c.value()))
+                }
+                Metric::Gauge(g) => self.report_gauge(key.key(), g.as_ref()),
+                Metric::UniqueCounter(c) => {
+                    report!(self, "{}{}.value={}", self.prefix, key.key(), c.value())
+                }
+                Metric::Apdex(a) => self.report_apdex(key.key(), &state.apdex(key, a.snapshot())),
+                Metric::ExponentialHistogram(h) => {
+                    let snapshot = state.exponential(key, h.snapshot());
+                    self.report_exponential_histogram(key.key(), &snapshot)
+                }
+                Metric::BucketedHistogram(h) => {
+                    let snapshot = state.buckets(key, h.snapshot());
+                    self.report_bucketed_histogram(key.key(), &snapshot)
+                }
+                Metric::Timer(t) => {
+                    let latency = t.latency();
+                    let totals = state.histogram(key, &latency);
+                    self.report_rates(key.key(), t.rate());
+                    self.report_histogram(key.key(), &latency, totals);
+                    if let Some(apdex) = t.apdex() {
+                        self.report_apdex(key.key(), &state.apdex(key, apdex.snapshot()));
+                    }
+                }
+                Metric::Meter(m) => {
+                    let count = state.count(key, m.count());
+                    self.report_meter(key.key(), m.as_ref(), count)
+                }
+                Metric::Histogram(h) => {
+                    let snapshot = h.snapshot();
+                    let totals = state.histogram(key, &snapshot);
+                    self.report_histogram(key.key(), &snapshot, totals)
                 }
             }
+        }
+    }
 
-            thread::sleep(Duration::from_secs(self.interval_secs));
-        };
-
-        thread::spawn(looper);
+    fn report_meter(&self, name: &str, meter: &Meter, count: u64) {
+        self.report_rates(name, meter);
+        self.report_count(name, count);
     }
 
-    fn report_meter(&self, name: &str, meter: &Meter) {
-        log!(self.level, "{}{}.m1={}", self.prefix, name, meter.m1_rate());
-        log!(self.level, "{}{}.m5={}", self.prefix, name, meter.m5_rate());
-        log!(
-            self.level,
-            "{}{}.m15={}",
-            self.prefix,
-            name,
-            meter.m15_rate()
-        );
-        log!(
-            self.level,
-            "{}{}.count={}",
-            self.prefix,
-            name,
-            meter.count()
-        );
+    fn report_rates(&self, name: &str, meter: &Meter) {
+        for rate in self.reporting_options.rates.iter() {
+            report!(
+                self,
+                "{}{}.{}={}",
+                self.prefix,
+                name,
+                rate.name(),
+                rate.value(meter)
+            );
+        }
     }
 
     fn report_gauge(&self, name: &str, gauge: &Gauge) {
         let value = gauge.value();
-        log!(self.level, "{}{}.value={}", self.prefix, name, value);
+        report!(self, "{}{}.value={}", self.prefix, name, value);
     }
 
-    fn report_histogram(&self, name: &str, snapshot: &HistogramSnapshot) {
-        log!(
-            self.level,
-            "{}{}.p50={}",
-            self.prefix,
-            name,
-            snapshot.quantile(0.5)
-        );
-        log!(
-            self.level,
-            "{}{}.p75={}",
-            self.prefix,
-            name,
-            snapshot.quantile(0.75)
-        );
-        log!(
-            self.level,
-            "{}{}.p90={}",
-            self.prefix,
-            name,
-            snapshot.quantile(0.9)
-        );
-        log!(
-            self.level,
-            "{}{}.p99={}",
+    fn report_histogram(&self, name: &str, snapshot: &HistogramSnapshot, totals: (u64, u64)) {
+        let (count, sum) = totals;
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            report!(
+                self,
+                "{}{}.{}={}",
+                self.prefix,
+                name,
+                pname,
+                snapshot.quantile(q)
+            );
+        }
+        report!(self, "{}{}.max={}", self.prefix, name, snapshot.max());
+        report!(self, "{}{}.min={}", self.prefix, name, snapshot.min());
+        report!(self, "{}{}.mean={}", self.prefix, name, snapshot.mean());
+        report!(self, "{}{}.count={}", self.prefix, name, count);
+        report!(self, "{}{}.sum={}", self.prefix, name, sum);
+    }
+
+    fn report_bucketed_histogram(&self, name: &str, snapshot: &BucketedHistogramSnapshot) {
+        for (le, count) in snapshot.iter_cumulative() {
+            report!(self, "{}{}.le_{}={}", self.prefix, name, le, count);
+        }
+        report!(self, "{}{}.count={}", self.prefix, name, snapshot.count());
+        report!(self, "{}{}.sum={}", self.prefix, name, snapshot.sum());
+    }
+
+    fn report_exponential_histogram(&self, name: &str, snapshot: &ExponentialHistogramSnapshot) {
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            report!(
+                self,
+                "{}{}.{}={}",
+                self.prefix,
+                name,
+                pname,
+                snapshot.quantile(q)
+            );
+        }
+        report!(self, "{}{}.count={}", self.prefix, name, snapshot.count());
+        report!(self, "{}{}.sum={}", self.prefix, name, snapshot.sum());
+    }
+
+    fn report_apdex(&self, name: &str, snapshot: &ApdexSnapshot) {
+        report!(self, "{}{}.apdex={}", self.prefix, name, snapshot.score());
+        report!(
+            self,
+            "{}{}.satisfied={}",
             self.prefix,
             name,
-            snapshot.quantile(0.99)
+            snapshot.satisfied()
         );
-        log!(
-            self.level,
-            "{}{}.p999={}",
+        report!(
+            self,
+            "{}{}.tolerating={}",
             self.prefix,
             name,
-            snapshot.quantile(0.999)
+            snapshot.tolerating()
         );
-        log!(self.level, "{}{}.max={}", self.prefix, name, snapshot.max());
-        log!(self.level, "{}{}.min={}", self.prefix, name, snapshot.min());
-        log!(
-            self.level,
-            "{}{}.mean={}",
+        report!(
+            self,
+            "{}{}.frustrated={}",
             self.prefix,
             name,
-            snapshot.mean()
+            snapshot.frustrated()
         );
     }
 
     fn report_counter(&self, name: &str, c: &Counter) {
-        log!(self.level, "{}{}.value={}", self.prefix, name, c.value());
+        report!(self, "{}{}.value={}", self.prefix, name, c.value());
+    }
+
+    fn report_count(&self, name: &str, count: u64) {
+        report!(self, "{}{}.count={}", self.prefix, name, count);
+    }
+}
+
+impl Reporter for LogReporter {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.log.report"));
+        }
+        let start = Instant::now();
+
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(self.temporality));
+        self.report_snapshot(snapshot, &mut state);
+        self.state = Some(state);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.success(start.elapsed());
+        }
+        Ok(())
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use log::{Log, Metadata, Record};
+    use metriki_core::MetricsRegistry;
+
+    use super::LogReporterBuilder;
+
+    /// A logger that keeps log lines in memory.
+    struct StubLogger(Mutex<Vec<String>>);
+
+    impl Log for StubLogger {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_final_report_on_stop() {
+        let logger: &'static StubLogger = Box::leak(Box::new(StubLogger(Mutex::new(Vec::new()))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let registry = Arc::new(MetricsRegistry::new());
+        let handle = LogReporterBuilder::default()
+            .registry(registry.clone())
+            .prefix("test.")
+            .interval_secs(3600)
+            .build()
+            .unwrap()
+            .start();
+
+        handle.flush();
+        registry.counter("requests").inc(3);
+        assert!(!logger
+            .0
+            .lock()
+            .unwrap()
+            .contains(&"test.requests.value=3".to_owned()));
 
-    fn report_timer(&self, name: &str, t: &Timer) {
-        self.report_meter(name, t.rate());
-        self.report_histogram(name, &t.latency());
+        drop(handle);
+        assert!(logger
+            .0
+            .lock()
+            .unwrap()
+            .contains(&"test.requests.value=3".to_owned()));
     }
 }
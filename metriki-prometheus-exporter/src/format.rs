@@ -0,0 +1,117 @@
+use prometheus::{PROTOBUF_FORMAT, TEXT_FORMAT};
+
+use crate::openmetrics::OPENMETRICS_FORMAT;
+
+/// Exposition formats supported by the exporter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// Classic Prometheus text format 0.0.4.
+    Text,
+    /// OpenMetrics 1.0 text format, the only one to carry exemplars.
+    OpenMetrics,
+    /// Length delimited protobuf, the only one to carry native histograms.
+    Protobuf,
+}
+
+impl Format {
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => TEXT_FORMAT,
+            Format::OpenMetrics => OPENMETRICS_FORMAT,
+            Format::Protobuf => PROTOBUF_FORMAT,
+        }
+    }
+
+    /// Choose the format by `Accept` header values.
+    ///
+    /// The supported media range with highest `q` wins, and the earlier one
+    /// on ties. It falls back to the classic text format when nothing
+    /// supported is acceptable.
+    pub(crate) fn negotiate<'a, I: IntoIterator<Item = &'a str>>(accepts: I) -> Format {
+        let mut best: Option<(Format, f64)> = None;
+        for range in accepts.into_iter().flat_map(|a| a.split(',')) {
+            if let Some((format, q)) = parse_media_range(range) {
+                if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                    best = Some((format, q));
+                }
+            }
+        }
+        best.map(|(format, _)| format).unwrap_or(Format::Text)
+    }
+}
+
+fn parse_media_range(range: &str) -> Option<(Format, f64)> {
+    let mut parts = range.split(';').map(str::trim);
+    let media_type = parts.next()?.to_ascii_lowercase();
+
+    let mut q = 1.0;
+    let mut params = Vec::new();
+    for param in parts {
+        let (k, v) = param.split_once('=')?;
+        let (k, v) = (k.trim().to_ascii_lowercase(), v.trim().trim_matches('"'));
+        if k == "q" {
+            q = v.parse().ok()?;
+        } else {
+            params.push((k, v));
+        }
+    }
+    let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| *v);
+
+    let format = match media_type.as_str() {
+        "application/vnd.google.protobuf" => {
+            if param("proto") != Some("io.prometheus.client.MetricFamily")
+                || param("encoding").is_some_and(|e| e != "delimited")
+            {
+                return None;
+            }
+            Format::Protobuf
+        }
+        "application/openmetrics-text" => {
+            if param("version").is_some_and(|v| v != "1.0.0") {
+                return None;
+            }
+            Format::OpenMetrics
+        }
+        "text/plain" => {
+            if param("version").is_some_and(|v| v != "0.0.4") {
+                return None;
+            }
+            Format::Text
+        }
+        "text/*" | "*/*" => Format::Text,
+        _ => return None,
+    };
+    Some((format, q))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Format::Text, Format::negotiate(vec![]));
+        assert_eq!(Format::Text, Format::negotiate(vec!["application/json"]));
+
+        // sent by prometheus
+        let accept = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(Format::OpenMetrics, Format::negotiate(vec![accept]));
+
+        // sent by prometheus with native histograms enabled
+        let accept = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3";
+        assert_eq!(Format::Protobuf, Format::negotiate(vec![accept]));
+
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(vec!["application/openmetrics-text; q=0.2", "text/plain"])
+        );
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(vec!["application/openmetrics-text;version=0.0.1"])
+        );
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(vec!["application/openmetrics-text;q=0"])
+        );
+    }
+}
@@ -1,17 +1,40 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 use derive_builder::Builder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use log::warn;
 use metriki_core::key::Key;
 use metriki_core::metrics::*;
+use metriki_core::reporter::{error_type, ReporterMetrics, Snapshot};
+use metriki_core::reporting::{
+    prometheus_label_name, prometheus_metric_name, ReportingOptions, Temporality, TemporalityState,
+};
 use metriki_core::MetricsRegistry;
 use prometheus::proto::{
-    Counter as PromethuesCount, Gauge as PromethuesGauge, LabelPair, Metric as PrometheusMetric,
-    MetricFamily, MetricType, Quantile, Summary,
+    Bucket, Counter as PromethuesCount, Gauge as PromethuesGauge, Histogram as PrometheusHistogram,
+    LabelPair, Metric as PrometheusMetric, MetricFamily, MetricType, Quantile, Summary,
 };
-use prometheus::{Encoder, TextEncoder};
-use tiny_http::{Response, Server};
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+use tiny_http::{Header, Request, Response, Server};
+
+mod format;
+mod native;
+mod openmetrics;
+mod push;
+
+use format::Format;
+use openmetrics::Exemplars;
+pub use push::{PushGateway, PushGatewayBuilder, PushMethod};
 
 #[derive(Builder)]
 pub struct PrometheusExporter {
@@ -20,8 +43,27 @@ pub struct PrometheusExporter {
     host: String,
     #[builder(setter)]
     port: u16,
+    /// Path to serve metrics on.
+    #[builder(setter(into), default = "\"/metrics\".to_string()")]
+    path: String,
+    /// Path of the health endpoint, which answers `OK` while the exporter
+    /// is running.
+    #[builder(setter(into), default = "\"/health\".to_string()")]
+    health_path: String,
     #[builder(default, setter(into))]
     prefix: String,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Export rates of meters and timers selected in `reporting_options` as
+    /// gauges, named like `{name}_m1_rate`. They are off by default as
+    /// Prometheus computes rates from counts.
+    #[builder(default)]
+    export_rates: bool,
+    /// Registry to record metrics of scrapes in, as `ReporterMetrics`
+    /// prefixed with `metriki.prometheus.scrape`. It can be the exported
+    /// registry itself.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
 }
 
 fn new_counter(v: f64) -> PrometheusMetric {
@@ -53,41 +95,255 @@ fn new_quantile(f: f64, s: &HistogramSnapshot) -> Quantile {
     q
 }
 
+/// Handle of a started exporter.
+///
+/// The server keeps running when the handle is dropped, call `shutdown` to
+/// stop it.
+pub struct ExporterHandle {
+    server: Arc<Server>,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Debug for ExporterHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("ExporterHandle")
+            .field("addr", &self.local_addr())
+            .finish()
+    }
+}
+
+impl ExporterHandle {
+    /// Address the server listens on, useful when it's started on port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Stop the server, and wait for the request in progress.
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.server.unblock();
+        let _ = self.thread.join();
+    }
+}
+
 impl PrometheusExporter {
-    pub fn start(self) {
+    /// Start the http server in a background thread.
+    ///
+    /// Metrics are served on `path`, and `health_path` answers `OK` for
+    /// liveness checks. Other paths get `404 Not Found`. Returns an error if
+    /// the server can't listen on given address.
+    pub fn start(self) -> Result<ExporterHandle, Box<dyn Error + Send + Sync>> {
         let addr = format!("{}:{}", self.host, self.port);
-        let server = Server::http(addr).expect("Failed to start promethues exporter server.");
-        let encoder = TextEncoder::new();
-
-        let looper = move || loop {
-            if let Ok(req) = server.recv() {
-                let metrics = self.registry.snapshots();
-                let metric_families: Vec<MetricFamily> = metrics
-                    .iter()
-                    .map(|(key, metric)| match metric {
-                        Metric::Counter(c) => self.report_counter(key, c.as_ref()),
-                        Metric::Gauge(g) => self.report_gauge(key, g.as_ref()),
-                        Metric::Timer(t) => self.report_timer(key, t.as_ref()),
-                        Metric::Meter(m) => self.report_meter(key, m.as_ref()),
-                        Metric::Histogram(h) => self.report_histogram(key, &h.snapshot()),
-                    })
-                    .collect();
+        let server = Arc::new(Server::http(addr)?);
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let server_ref = server.clone();
+        let shutdown_ref = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("metriki-prometheus-exporter".to_owned())
+            .spawn(move || self.serve(&server_ref, &shutdown_ref))?;
+
+        Ok(ExporterHandle {
+            server,
+            shutdown,
+            thread,
+        })
+    }
 
-                let mut buffer = Vec::new();
-                encoder.encode(&metric_families, &mut buffer).unwrap();
+    fn serve(&self, server: &Server, shutdown: &AtomicBool) {
+        // prometheus expects counts since start of the process
+        let mut state = TemporalityState::new(Temporality::Cumulative);
+        let scrape_metrics = self
+            .self_metrics
+            .clone()
+            .map(|registry| ReporterMetrics::new(registry, "metriki.prometheus.scrape"));
+
+        loop {
+            let req = match server.recv() {
+                Ok(req) => req,
+                Err(_) if shutdown.load(Ordering::SeqCst) => return,
+                Err(e) => {
+                    warn!("Error on receiving request {}", e);
+                    continue;
+                }
+            };
+
+            let path = req.url().split('?').next().unwrap_or_default();
+            let result = if path == self.path {
+                self.scrape(req, &mut state, scrape_metrics.as_ref())
+            } else if path == self.health_path {
+                req.respond(Response::from_string("OK"))
+            } else {
+                req.respond(Response::from_string("Not Found").with_status_code(404))
+            };
+
+            if let Err(e) = result {
+                warn!("Error on response {}", e);
+            }
+        }
+    }
 
-                if let Err(e) = req.respond(Response::from_data(buffer)) {
-                    warn!("Error on response {}", e);
+    fn scrape(
+        &self,
+        req: Request,
+        state: &mut TemporalityState,
+        scrape_metrics: Option<&ReporterMetrics>,
+    ) -> io::Result<()> {
+        let start = Instant::now();
+        let mut exemplars = Exemplars::default();
+        let snapshot = self.registry.snapshots();
+        let metric_families = match self.collector().collect(&snapshot, state, &mut exemplars) {
+            Ok(families) => families,
+            Err(e) => {
+                warn!("Failed to export metrics, {}", e);
+                if let Some(metrics) = scrape_metrics {
+                    metrics.failure(start.elapsed(), "conflicting_types");
                 }
+                return req.respond(Response::from_string(e).with_status_code(500));
             }
         };
 
-        thread::spawn(looper);
+        let accepts = req
+            .headers()
+            .iter()
+            .filter(|h| h.field.equiv("Accept"))
+            .map(|h| h.value.as_str());
+        let format = Format::negotiate(accepts);
+        let body = match format {
+            Format::Protobuf => {
+                let mut buffer = Vec::new();
+                ProtobufEncoder::new()
+                    .encode(&metric_families, &mut buffer)
+                    .unwrap();
+                buffer
+            }
+            Format::OpenMetrics => openmetrics::encode(&metric_families, &exemplars).into_bytes(),
+            Format::Text => {
+                let mut buffer = Vec::new();
+                TextEncoder::new()
+                    .encode(&metric_families, &mut buffer)
+                    .unwrap();
+                buffer
+            }
+        };
+
+        let points: usize = metric_families.iter().map(|f| f.get_metric().len()).sum();
+        let gzip = accepts_gzip(&req);
+        let body = if gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body)?;
+            encoder.finish()?
+        } else {
+            body
+        };
+
+        let bytes = body.len();
+        let content_type = format.content_type();
+        let mut response = Response::from_data(body)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], content_type).unwrap());
+        if gzip {
+            response = response
+                .with_header(Header::from_bytes(&b"Content-Encoding"[..], &b"gzip"[..]).unwrap());
+        }
+        let result = req.respond(response);
+
+        if let Some(metrics) = scrape_metrics {
+            match &result {
+                Ok(()) => {
+                    metrics.add_points(points as u64);
+                    metrics.add_bytes(bytes as u64);
+                    metrics.success(start.elapsed());
+                }
+                Err(e) => metrics.failure(start.elapsed(), &error_type(e)),
+            }
+        }
+        result
+    }
+
+    fn collector(&self) -> Collector<'_> {
+        Collector {
+            prefix: &self.prefix,
+            reporting_options: &self.reporting_options,
+            export_rates: self.export_rates,
+        }
+    }
+}
+
+/// Converts metrics into Prometheus metric families.
+pub(crate) struct Collector<'a> {
+    prefix: &'a str,
+    reporting_options: &'a ReportingOptions,
+    export_rates: bool,
+}
+
+impl Collector<'_> {
+    /// Collect metric families, grouped by name.
+    pub(crate) fn collect(
+        &self,
+        metrics: &Snapshot,
+        state: &mut TemporalityState,
+        exemplars: &mut Exemplars,
+    ) -> Result<Vec<MetricFamily>, String> {
+        group_families(self.collect_all(metrics, state, exemplars))
+    }
+
+    fn collect_all(
+        &self,
+        metrics: &Snapshot,
+        state: &mut TemporalityState,
+        exemplars: &mut Exemplars,
+    ) -> Vec<MetricFamily> {
+        let mut metric_families: Vec<MetricFamily> = metrics
+            .iter()
+            .map(|(key, metric)| match metric {
+                Metric::Counter(c) => self.report_counter(key, c.as_ref()),
+                Metric::MonotonicCounter(c) => self.report_monotonic_counter(key, c.as_ref()),
+                Metric::Gauge(g) => self.report_gauge(key, g.as_ref()),
+                Metric::UniqueCounter(c) => self.report_unique_counter(key, c.as_ref()),
+                Metric::Apdex(a) => self.report_apdex(key.key(), key, a.as_ref()),
+                Metric::ExponentialHistogram(h) => {
+                    self.report_exponential_histogram(key, &h.snapshot())
+                }
+                Metric::BucketedHistogram(h) => {
+                    self.report_bucketed_histogram(key, &h.snapshot(), exemplars)
+                }
+                Metric::Timer(t) => self.report_timer(key, t.as_ref(), state),
+                Metric::Meter(m) => self.report_meter(key, m.as_ref()),
+                Metric::Histogram(h) => self.report_histogram(key, &h.snapshot(), state),
+            })
+            .collect();
+        // apdex counts, and apdex score of timers if enabled
+        for (key, metric) in metrics.iter() {
+            match metric {
+                Metric::Apdex(a) => {
+                    metric_families.extend(self.report_apdex_counts(key.key(), key, a.as_ref()))
+                }
+                Metric::Timer(t) => {
+                    if let Some(apdex) = t.apdex() {
+                        let name = format!("{}_apdex", key.key());
+                        metric_families.push(self.report_apdex(&name, key, apdex));
+                        metric_families.extend(self.report_apdex_counts(&name, key, apdex));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.export_rates {
+            for (key, metric) in metrics.iter() {
+                match metric {
+                    Metric::Meter(m) => metric_families.extend(self.report_rates(key, m.as_ref())),
+                    Metric::Timer(t) => metric_families.extend(self.report_rates(key, t.rate())),
+                    _ => {}
+                }
+            }
+        }
+        metric_families
     }
 
     fn new_metric_family(&self, name: &str, mtype: MetricType) -> MetricFamily {
         let mut family = MetricFamily::new();
-        family.set_name(format!("{}{}", self.prefix, name));
+        family.set_name(prometheus_metric_name(&format!("{}{}", self.prefix, name)));
         family.set_field_type(mtype);
 
         family
@@ -102,6 +358,20 @@ impl PrometheusExporter {
         family
     }
 
+    fn report_rates(&self, key: &Key, meter: &Meter) -> Vec<MetricFamily> {
+        self.reporting_options
+            .rates
+            .iter()
+            .map(|rate| {
+                let name = format!("{}_{}", key.key(), rate.rate_name());
+                let mut family = self.new_metric_family(&name, MetricType::GAUGE);
+                let metric = setup_tags(key, new_gauge(rate.value(meter)));
+                family.set_metric(vec![metric].into());
+                family
+            })
+            .collect()
+    }
+
     fn report_gauge(&self, key: &Key, gauge: &Gauge) -> MetricFamily {
         let mut family = self.new_metric_family(key.key(), MetricType::GAUGE);
 
@@ -110,25 +380,164 @@ impl PrometheusExporter {
         family
     }
 
-    fn report_histogram(&self, key: &Key, snapshot: &HistogramSnapshot) -> MetricFamily {
+    fn report_histogram(
+        &self,
+        key: &Key,
+        snapshot: &HistogramSnapshot,
+        state: &mut TemporalityState,
+    ) -> MetricFamily {
+        let (count, sum) = state.histogram(key, snapshot);
         let mut family = self.new_metric_family(key.key(), MetricType::SUMMARY);
 
         let mut metric = setup_tags(key, PrometheusMetric::new());
-        let quantiles = vec![
-            new_quantile(0.5, snapshot),
-            new_quantile(0.75, snapshot),
-            new_quantile(0.9, snapshot),
-            new_quantile(0.99, snapshot),
-            new_quantile(0.999, snapshot),
-        ];
+        let quantiles: Vec<Quantile> = self
+            .reporting_options
+            .percentiles
+            .iter()
+            .map(|q| new_quantile(*q, snapshot))
+            .collect();
         let mut summary = Summary::new();
         summary.set_quantile(quantiles.into());
+        summary.set_sample_count(count);
+        summary.set_sample_sum(sum as f64);
         metric.set_summary(summary);
         family.set_metric(vec![metric].into());
         family
     }
 
+    fn report_bucketed_histogram(
+        &self,
+        key: &Key,
+        snapshot: &BucketedHistogramSnapshot,
+        exemplars: &mut Exemplars,
+    ) -> MetricFamily {
+        let mut family = self.new_metric_family(key.key(), MetricType::HISTOGRAM);
+
+        // the `+Inf` bucket is written by encoders from sample count
+        let buckets: Vec<Bucket> = snapshot
+            .iter_cumulative()
+            .filter(|(le, _)| le.is_finite())
+            .map(|(le, count)| {
+                let mut bucket = Bucket::new();
+                bucket.set_upper_bound(le);
+                bucket.set_cumulative_count(count);
+                bucket
+            })
+            .collect();
+        let mut histogram = PrometheusHistogram::new();
+        histogram.set_bucket(buckets.into());
+        histogram.set_sample_count(snapshot.count());
+        histogram.set_sample_sum(snapshot.sum());
+
+        let mut metric = setup_tags(key, PrometheusMetric::new());
+        metric.set_histogram(histogram);
+        for (le, exemplar) in snapshot.iter_exemplars() {
+            exemplars.insert(&family, &metric, Some(le), exemplar.clone());
+        }
+        family.set_metric(vec![metric].into());
+        family
+    }
+
+    fn report_exponential_histogram(
+        &self,
+        key: &Key,
+        snapshot: &ExponentialHistogramSnapshot,
+    ) -> MetricFamily {
+        let mut family = self.new_metric_family(key.key(), MetricType::HISTOGRAM);
+
+        // classic buckets for text formats, ordered by upper bound
+        let mut buckets = Vec::new();
+        let mut cumulative = 0;
+        let negative: Vec<(i32, u64)> = snapshot.negative_buckets().collect();
+        for (i, c) in negative.into_iter().rev() {
+            cumulative += c;
+            buckets.push((-snapshot.bucket_bounds(i).0, cumulative));
+        }
+        cumulative += snapshot.zero_count();
+        buckets.push((snapshot.zero_threshold(), cumulative));
+        for (i, c) in snapshot.positive_buckets() {
+            cumulative += c;
+            buckets.push((snapshot.bucket_bounds(i).1, cumulative));
+        }
+
+        let buckets: Vec<Bucket> = buckets
+            .into_iter()
+            .map(|(le, count)| {
+                let mut bucket = Bucket::new();
+                bucket.set_upper_bound(le);
+                bucket.set_cumulative_count(count);
+                bucket
+            })
+            .collect();
+        let mut histogram = PrometheusHistogram::new();
+        histogram.set_bucket(buckets.into());
+        histogram.set_sample_count(snapshot.count());
+        histogram.set_sample_sum(snapshot.sum());
+        native::set_native_histogram(&mut histogram, snapshot);
+
+        let mut metric = setup_tags(key, PrometheusMetric::new());
+        metric.set_histogram(histogram);
+        family.set_metric(vec![metric].into());
+        family
+    }
+
     fn report_counter(&self, key: &Key, c: &Counter) -> MetricFamily {
+        // counter may go down so it's exported as gauge
+        let mut family = self.new_metric_family(key.key(), MetricType::GAUGE);
+
+        let metric = setup_tags(key, new_gauge(c.value() as f64));
+
+        family.set_metric(vec![metric].into());
+        family
+    }
+
+    fn report_unique_counter(&self, key: &Key, c: &UniqueCounter) -> MetricFamily {
+        let mut family = self.new_metric_family(key.key(), MetricType::GAUGE);
+
+        let metric = setup_tags(key, new_gauge(c.value() as f64));
+
+        family.set_metric(vec![metric].into());
+        family
+    }
+
+    fn report_apdex(&self, name: &str, key: &Key, apdex: &Apdex) -> MetricFamily {
+        let mut family = self.new_metric_family(name, MetricType::GAUGE);
+
+        let metric = setup_tags(key, new_gauge(apdex.score()));
+
+        family.set_metric(vec![metric].into());
+        family
+    }
+
+    /// Apdex counts as counters, so the score can be calculated over a range
+    /// with `rate()`, and the threshold in milliseconds as a gauge.
+    fn report_apdex_counts(&self, name: &str, key: &Key, apdex: &Apdex) -> Vec<MetricFamily> {
+        let snapshot = apdex.snapshot();
+        let counts = [
+            ("satisfied", snapshot.satisfied()),
+            ("tolerating", snapshot.tolerating()),
+            ("frustrated", snapshot.frustrated()),
+        ];
+        let mut families: Vec<MetricFamily> = counts
+            .iter()
+            .map(|(kind, count)| {
+                let name = format!("{}_{}", name, kind);
+                let mut family = self.new_metric_family(&name, MetricType::COUNTER);
+                let metric = setup_tags(key, new_counter(*count as f64));
+                family.set_metric(vec![metric].into());
+                family
+            })
+            .collect();
+
+        let name = format!("{}_threshold", name);
+        let mut family = self.new_metric_family(&name, MetricType::GAUGE);
+        let threshold = apdex.threshold().as_secs_f64() * 1000.0;
+        family.set_metric(vec![setup_tags(key, new_gauge(threshold))].into());
+        families.push(family);
+        families
+    }
+
+    fn report_monotonic_counter(&self, key: &Key, c: &MonotonicCounter) -> MetricFamily {
         let mut family = self.new_metric_family(key.key(), MetricType::COUNTER);
 
         let counter = setup_tags(key, new_counter(c.value() as f64));
@@ -137,37 +546,314 @@ impl PrometheusExporter {
         family
     }
 
-    fn report_timer(&self, key: &Key, t: &Timer) -> MetricFamily {
-        let rate = t.rate();
+    fn report_timer(&self, key: &Key, t: &Timer, state: &mut TemporalityState) -> MetricFamily {
         let latency = t.latency();
+        // count of completed samples, matching the sum, as started contexts
+        // are counted by the rate before they complete
+        let (count, sum) = state.histogram(key, &latency);
 
         let mut family = self.new_metric_family(key.key(), MetricType::SUMMARY);
         let mut metric = setup_tags(key, PrometheusMetric::new());
-        let quantiles = vec![
-            new_quantile(0.5, &latency),
-            new_quantile(0.75, &latency),
-            new_quantile(0.9, &latency),
-            new_quantile(0.99, &latency),
-            new_quantile(0.999, &latency),
-        ];
+        let quantiles: Vec<Quantile> = self
+            .reporting_options
+            .percentiles
+            .iter()
+            .map(|q| new_quantile(*q, &latency))
+            .collect();
         let mut summary = Summary::new();
         summary.set_quantile(quantiles.into());
-        summary.set_sample_count(rate.count());
+        summary.set_sample_count(count);
+        summary.set_sample_sum(sum as f64);
         metric.set_summary(summary);
         family.set_metric(vec![metric].into());
         family
     }
 }
 
+/// Merge families of the same name, like series of a metric with different
+/// tags, into one family sorted by name. Prometheus requires each name to
+/// appear once in the exposition, with one type.
+fn group_families(families: Vec<MetricFamily>) -> Result<Vec<MetricFamily>, String> {
+    let mut grouped: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for mut family in families {
+        match grouped.entry(family.get_name().to_owned()) {
+            Entry::Vacant(e) => {
+                e.insert(family);
+            }
+            Entry::Occupied(mut e) => {
+                let existing = e.get_mut();
+                if existing.get_field_type() != family.get_field_type() {
+                    return Err(format!(
+                        "Metric {} is registered as both {:?} and {:?}",
+                        family.get_name(),
+                        existing.get_field_type(),
+                        family.get_field_type()
+                    ));
+                }
+                existing.mut_metric().extend(family.take_metric());
+            }
+        }
+    }
+    Ok(grouped.into_values().collect())
+}
+
+/// Whether the client accepts gzip encoding, by `Accept-Encoding` header.
+fn accepts_gzip(req: &Request) -> bool {
+    req.headers()
+        .iter()
+        .filter(|h| h.field.equiv("Accept-Encoding"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            // `q=0` means not acceptable
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && q > 0.0
+        })
+}
+
 fn setup_tags(key: &Key, mut metric: PrometheusMetric) -> PrometheusMetric {
     let labels = metric.mut_label();
 
     for tag in key.tags() {
         let mut lp = LabelPair::new();
-        lp.set_name(tag.key().to_string());
+        lp.set_name(prometheus_label_name(tag.key()));
         lp.set_value(tag.value().to_string());
 
         labels.push(lp);
     }
     metric
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use flate2::read::GzDecoder;
+    use metriki_core::key::Tag;
+    use metriki_core::metrics::Buckets;
+    use metriki_core::reporting::{Rate, ReportingOptions, Temporality, TemporalityState};
+    use metriki_core::MetricsRegistry;
+    use prometheus::proto::MetricType;
+    use prometheus::{Encoder, TextEncoder};
+
+    use super::openmetrics::Exemplars;
+    use super::{PrometheusExporter, PrometheusExporterBuilder};
+
+    /// Send a GET request, and returns the response head and body.
+    fn get(addr: SocketAddr, path: &str, headers: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+            path, headers
+        )
+        .unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_http_server() {
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.counter("requests").inc(3);
+
+        let handle = PrometheusExporterBuilder::default()
+            .registry(registry)
+            .host("127.0.0.1")
+            .port(0)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let (head, body) = get(addr, "/metrics", "");
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(String::from_utf8(body).unwrap().contains("requests 3"));
+
+        let (head, body) = get(addr, "/metrics?x=1", "Accept-Encoding: gzip, br\r\n");
+        assert!(head.contains("Content-Encoding: gzip"));
+        let mut text = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert!(text.contains("requests 3"));
+
+        let (head, body) = get(
+            addr,
+            "/metrics",
+            "Accept: application/openmetrics-text;version=1.0.0,text/plain;q=0.5\r\n",
+        );
+        assert!(head.contains("Content-Type: application/openmetrics-text; version=1.0.0"));
+        assert!(String::from_utf8(body).unwrap().ends_with("# EOF\n"));
+
+        let (head, _) = get(addr, "/metrics", "Accept-Encoding: gzip;q=0\r\n");
+        assert!(!head.contains("Content-Encoding"));
+
+        let (head, body) = get(addr, "/health", "");
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(b"OK".to_vec(), body);
+
+        let (head, _) = get(addr, "/", "");
+        assert!(head.starts_with("HTTP/1.1 404"));
+
+        handle.shutdown();
+
+        // the listener is closed shortly after shutdown
+        let closed = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            TcpStream::connect(addr).is_err()
+        });
+        assert!(closed);
+    }
+
+    #[test]
+    fn test_self_metrics_scrape() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let handle = PrometheusExporterBuilder::default()
+            .registry(registry.clone())
+            .host("127.0.0.1")
+            .port(0)
+            .self_metrics(registry)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        get(addr, "/metrics", "");
+        let (_, body) = get(addr, "/metrics", "");
+        let text = String::from_utf8(body).unwrap();
+        handle.shutdown();
+
+        assert!(text.contains("\nmetriki_prometheus_scrape_points "));
+        let valid = |name: &str| {
+            !name.starts_with(|c: char| c.is_ascii_digit())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        };
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            assert!(valid(name), "invalid metric name in {}", line);
+            if let Some(labels) = line.split_once('{').and_then(|(_, l)| l.split_once('}')) {
+                for label in labels.0.split(',') {
+                    assert!(valid(label.split('=').next().unwrap()), "{}", line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_families() {
+        let registry = Arc::new(MetricsRegistry::new());
+        for method in ["GET", "POST"] {
+            registry
+                .monotonic_counter_with_tags("requests", vec![Tag::new("method", method)])
+                .inc(1);
+        }
+        registry.counter("connections").inc(1);
+
+        let exporter = PrometheusExporterBuilder::default()
+            .registry(registry.clone())
+            .port(0)
+            .build()
+            .unwrap();
+        let mut state = TemporalityState::new(Temporality::Cumulative);
+
+        let families = exporter
+            .collector()
+            .collect(
+                &exporter.registry.snapshots(),
+                &mut state,
+                &mut Exemplars::default(),
+            )
+            .unwrap();
+        assert_eq!(2, families.len());
+        assert_eq!("connections", families[0].get_name());
+        assert_eq!("requests", families[1].get_name());
+        assert_eq!(MetricType::COUNTER, families[1].get_field_type());
+        assert_eq!(2, families[1].get_metric().len());
+
+        // counter is exported as gauge
+        registry.counter("requests").inc(1);
+        let error = exporter
+            .collector()
+            .collect(
+                &exporter.registry.snapshots(),
+                &mut state,
+                &mut Exemplars::default(),
+            )
+            .unwrap_err();
+        assert!(error.starts_with("Metric requests is registered as both "));
+    }
+
+    fn encode_text(exporter: &PrometheusExporter) -> String {
+        let mut state = TemporalityState::new(Temporality::Cumulative);
+        let families = exporter
+            .collector()
+            .collect(
+                &exporter.registry.snapshots(),
+                &mut state,
+                &mut Exemplars::default(),
+            )
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&families, &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_text_exposition() {
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.counter("connections").inc(2);
+        registry
+            .monotonic_counter_with_tags("requests", vec![Tag::new("method", "GET")])
+            .inc(5);
+        registry.gauge("pool_size", Box::new(|| 10.0));
+        registry.meter("jobs");
+        let histogram = registry.histogram("response_size");
+        let timer = registry.timer("latency");
+        timer.enable_apdex(Duration::from_millis(50));
+        let buckets = registry.bucketed_histogram("queue_time", Buckets::new(vec![10.0, 50.0]));
+        for i in 1..=100 {
+            histogram.update(i);
+            timer.update(Duration::from_millis(i));
+            buckets.update(i as f64);
+        }
+        // not counted in `app_latency_count` until completed
+        let _in_flight = timer.start();
+
+        let exporter = PrometheusExporterBuilder::default()
+            .registry(registry)
+            .port(0)
+            .prefix("app_")
+            .reporting_options(ReportingOptions {
+                percentiles: vec![0.5, 0.99],
+                // m1 is 0 until the first tick, which keeps the output stable
+                rates: vec![Rate::M1],
+            })
+            .export_rates(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            include_str!("../testdata/text_exposition.txt"),
+            encode_text(&exporter)
+        );
+    }
+}
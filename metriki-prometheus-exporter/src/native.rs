@@ -0,0 +1,178 @@
+use metriki_core::metrics::ExponentialHistogramSnapshot;
+use prometheus::proto::Histogram;
+use protobuf::Message;
+
+// Field numbers of native histogram in `io.prometheus.client.Histogram`,
+// which are not available in the generated code of prometheus crate.
+const SCHEMA: u32 = 5;
+const ZERO_THRESHOLD: u32 = 6;
+const ZERO_COUNT: u32 = 7;
+const NEGATIVE_SPAN: u32 = 9;
+const NEGATIVE_DELTA: u32 = 10;
+const POSITIVE_SPAN: u32 = 12;
+const POSITIVE_DELTA: u32 = 13;
+
+/// Write buckets of an exponential histogram as Prometheus native histogram.
+///
+/// Native histogram fields are added as unknown fields, so they are only
+/// written by the protobuf encoder. Text formats use classic buckets of the
+/// same histogram.
+///
+/// Bucket `i` of the snapshot is `(base^i, base^(i+1)]`, while Prometheus
+/// defines bucket `i` as `(base^(i-1), base^i]`, so indexes are shifted by
+/// one.
+pub(crate) fn set_native_histogram(
+    histogram: &mut Histogram,
+    snapshot: &ExponentialHistogramSnapshot,
+) {
+    let fields = histogram.mut_unknown_fields();
+
+    fields.add_varint(SCHEMA, zigzag32(snapshot.scale()) as u64);
+    fields.add_fixed64(ZERO_THRESHOLD, snapshot.zero_threshold().to_bits());
+    fields.add_varint(ZERO_COUNT, snapshot.zero_count());
+
+    let negative: Vec<(i32, u64)> = snapshot
+        .negative_buckets()
+        .map(|(i, c)| (i + 1, c))
+        .collect();
+    for span in spans(&negative) {
+        fields.add_length_delimited(NEGATIVE_SPAN, span);
+    }
+    for delta in deltas(&negative) {
+        fields.add_varint(NEGATIVE_DELTA, zigzag(delta));
+    }
+
+    let positive: Vec<(i32, u64)> = snapshot
+        .positive_buckets()
+        .map(|(i, c)| (i + 1, c))
+        .collect();
+    for span in spans(&positive) {
+        fields.add_length_delimited(POSITIVE_SPAN, span);
+    }
+    for delta in deltas(&positive) {
+        fields.add_varint(POSITIVE_DELTA, zigzag(delta));
+    }
+}
+
+/// Encode `BucketSpan` messages of consecutive bucket indexes. Offset of
+/// the first span is its start index, offsets of later spans are the gap
+/// from the end of the previous span.
+fn spans(buckets: &[(i32, u64)]) -> Vec<Vec<u8>> {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut next_index = 0;
+
+    for (idx, (i, _)) in buckets.iter().enumerate() {
+        match spans.last_mut() {
+            Some((_, length)) if *i == next_index => *length += 1,
+            _ => spans.push((if idx == 0 { *i } else { *i - next_index }, 1)),
+        }
+        next_index = *i + 1;
+    }
+
+    spans
+        .into_iter()
+        .map(|(offset, length)| {
+            let mut buf = vec![0x08];
+            write_varint(&mut buf, zigzag32(offset) as u64);
+            buf.push(0x10);
+            write_varint(&mut buf, length as u64);
+            buf
+        })
+        .collect()
+}
+
+/// Bucket counts as delta to the previous bucket.
+fn deltas(buckets: &[(i32, u64)]) -> Vec<i64> {
+    let mut last = 0i64;
+    buckets
+        .iter()
+        .map(|(_, c)| {
+            let delta = *c as i64 - last;
+            last = *c as i64;
+            delta
+        })
+        .collect()
+}
+
+/// Encode `sint64` values.
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+/// Encode `sint32` values, like schema and span offsets.
+fn zigzag32(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use metriki_core::MetricsRegistry;
+    use prometheus::proto::Histogram;
+    use protobuf::Message;
+
+    use super::{deltas, set_native_histogram, spans, zigzag, zigzag32};
+    use super::{NEGATIVE_DELTA, NEGATIVE_SPAN, POSITIVE_DELTA, POSITIVE_SPAN, SCHEMA};
+
+    fn unzigzag(v: u64) -> i64 {
+        ((v >> 1) as i64) ^ -((v & 1) as i64)
+    }
+
+    /// Decode `(offset, length)` of a `BucketSpan` with single byte varints.
+    fn decode_span(buf: &[u8]) -> (i64, u64) {
+        assert_eq!(4, buf.len());
+        assert_eq!((0x08, 0x10), (buf[0], buf[2]));
+        (unzigzag(buf[1] as u64), buf[3] as u64)
+    }
+
+    #[test]
+    fn test_native_bucket_index() {
+        let registry = MetricsRegistry::new();
+        let histogram = registry.exponential_histogram("size", 0);
+        histogram.update(3.0);
+        histogram.update(-3.0);
+
+        let mut native = Histogram::new();
+        set_native_histogram(&mut native, &histogram.snapshot());
+        let fields = native.get_unknown_fields();
+
+        assert_eq!(vec![0], fields.get(SCHEMA).unwrap().varint);
+        for (span, delta) in [
+            (POSITIVE_SPAN, POSITIVE_DELTA),
+            (NEGATIVE_SPAN, NEGATIVE_DELTA),
+        ] {
+            let spans = &fields.get(span).unwrap().length_delimited;
+            assert_eq!(1, spans.len());
+            let (offset, length) = decode_span(&spans[0]);
+            assert_eq!(1, length);
+            assert_eq!(vec![zigzag(1)], fields.get(delta).unwrap().varint);
+
+            // bucket i of schema 0 is (2^(i-1), 2^i]
+            let (lower, upper) = (2f64.powi(offset as i32 - 1), 2f64.powi(offset as i32));
+            assert!(lower < 3.0 && 3.0 <= upper);
+        }
+    }
+
+    #[test]
+    fn test_spans_and_deltas() {
+        let buckets = vec![(-2, 3), (-1, 1), (3, 4)];
+
+        assert_eq!(
+            vec![
+                vec![0x08, zigzag(-2) as u8, 0x10, 2],
+                vec![0x08, zigzag(3) as u8, 0x10, 1]
+            ],
+            spans(&buckets)
+        );
+        assert_eq!(vec![3, -2, 3], deltas(&buckets));
+        assert_eq!(3, zigzag(-2));
+        assert_eq!(3, zigzag32(-2));
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use metriki_core::metrics::Exemplar;
+use prometheus::proto::{LabelPair, Metric as PrometheusMetric, MetricFamily, MetricType};
+
+pub(crate) const OPENMETRICS_FORMAT: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+type ExemplarKey = (String, Vec<(String, String)>);
+
+/// Exemplars of histogram buckets and counters, indexed by metric family
+/// name and labels.
+///
+/// OpenMetrics 1.0 allows exemplars only on `_bucket` and `_total` samples.
+#[derive(Debug, Default)]
+pub(crate) struct Exemplars {
+    inner: HashMap<ExemplarKey, Vec<(Option<f64>, Exemplar)>>,
+}
+
+impl Exemplars {
+    /// Add the exemplar of a histogram bucket by its upper bound `le`, or of
+    /// a counter with `None`.
+    pub(crate) fn insert(
+        &mut self,
+        family: &MetricFamily,
+        metric: &PrometheusMetric,
+        le: Option<f64>,
+        exemplar: Exemplar,
+    ) {
+        self.inner
+            .entry(exemplar_key(family, metric))
+            .or_default()
+            .push((le, exemplar));
+    }
+
+    fn get(
+        &self,
+        family: &MetricFamily,
+        metric: &PrometheusMetric,
+        le: Option<f64>,
+    ) -> Option<&Exemplar> {
+        self.inner
+            .get(&exemplar_key(family, metric))
+            .and_then(|v| v.iter().find(|(l, _)| *l == le))
+            .map(|(_, e)| e)
+    }
+}
+
+fn exemplar_key(family: &MetricFamily, metric: &PrometheusMetric) -> ExemplarKey {
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|lp| (lp.get_name().to_owned(), lp.get_value().to_owned()))
+        .collect();
+    (family.get_name().to_owned(), labels)
+}
+
+/// Encode metric families into OpenMetrics 1.0 text format.
+pub(crate) fn encode(families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut buf = String::new();
+
+    for family in families {
+        let name = family.get_name();
+        match family.get_field_type() {
+            MetricType::COUNTER => {
+                let name = name.strip_suffix("_total").unwrap_or(name);
+                write_metadata(&mut buf, name, "counter");
+                for metric in family.get_metric() {
+                    let total = format!("{}_total", name);
+                    write_sample(
+                        &mut buf,
+                        &total,
+                        metric.get_label(),
+                        None,
+                        metric.get_counter().get_value(),
+                        exemplars.get(family, metric, None),
+                    );
+                    buf.push('\n');
+                }
+            }
+            MetricType::GAUGE => {
+                write_metadata(&mut buf, name, "gauge");
+                for metric in family.get_metric() {
+                    write_sample(
+                        &mut buf,
+                        name,
+                        metric.get_label(),
+                        None,
+                        metric.get_gauge().get_value(),
+                        None,
+                    );
+                    buf.push('\n');
+                }
+            }
+            MetricType::HISTOGRAM => {
+                write_metadata(&mut buf, name, "histogram");
+                for metric in family.get_metric() {
+                    let histogram = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    for b in histogram.get_bucket() {
+                        let le = ("le", format_float(b.get_upper_bound()));
+                        write_sample(
+                            &mut buf,
+                            &bucket,
+                            metric.get_label(),
+                            Some(le),
+                            b.get_cumulative_count() as f64,
+                            exemplars.get(family, metric, Some(b.get_upper_bound())),
+                        );
+                        buf.push('\n');
+                    }
+                    if !histogram
+                        .get_bucket()
+                        .iter()
+                        .any(|b| b.get_upper_bound() == f64::INFINITY)
+                    {
+                        write_sample(
+                            &mut buf,
+                            &bucket,
+                            metric.get_label(),
+                            Some(("le", "+Inf".to_owned())),
+                            histogram.get_sample_count() as f64,
+                            exemplars.get(family, metric, Some(f64::INFINITY)),
+                        );
+                        buf.push('\n');
+                    }
+
+                    let sum = format!("{}_sum", name);
+                    write_sample(
+                        &mut buf,
+                        &sum,
+                        metric.get_label(),
+                        None,
+                        histogram.get_sample_sum(),
+                        None,
+                    );
+                    buf.push('\n');
+                    let count = format!("{}_count", name);
+                    write_sample(
+                        &mut buf,
+                        &count,
+                        metric.get_label(),
+                        None,
+                        histogram.get_sample_count() as f64,
+                        None,
+                    );
+                    buf.push('\n');
+                }
+            }
+            MetricType::SUMMARY => {
+                write_metadata(&mut buf, name, "summary");
+                for metric in family.get_metric() {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        let quantile = ("quantile", format_float(q.get_quantile()));
+                        write_sample(
+                            &mut buf,
+                            name,
+                            metric.get_label(),
+                            Some(quantile),
+                            q.get_value(),
+                            None,
+                        );
+                        buf.push('\n');
+                    }
+
+                    let sum = format!("{}_sum", name);
+                    write_sample(
+                        &mut buf,
+                        &sum,
+                        metric.get_label(),
+                        None,
+                        summary.get_sample_sum(),
+                        None,
+                    );
+                    buf.push('\n');
+                    let count = format!("{}_count", name);
+                    write_sample(
+                        &mut buf,
+                        &count,
+                        metric.get_label(),
+                        None,
+                        summary.get_sample_count() as f64,
+                        None,
+                    );
+                    buf.push('\n');
+                }
+            }
+            _ => {
+                write_metadata(&mut buf, name, "unknown");
+                for metric in family.get_metric() {
+                    write_sample(
+                        &mut buf,
+                        name,
+                        metric.get_label(),
+                        None,
+                        metric.get_untyped().get_value(),
+                        None,
+                    );
+                    buf.push('\n');
+                }
+            }
+        }
+    }
+
+    buf.push_str("# EOF\n");
+    buf
+}
+
+/// Units of OpenMetrics recognized from the suffix of metric names, which
+/// is required to match the unit.
+const UNITS: &[&str] = &[
+    "seconds",
+    "milliseconds",
+    "microseconds",
+    "nanoseconds",
+    "bytes",
+    "ratio",
+    "celsius",
+    "meters",
+    "grams",
+    "volts",
+    "amperes",
+    "joules",
+];
+
+fn write_metadata(buf: &mut String, name: &str, metric_type: &str) {
+    writeln!(buf, "# TYPE {} {}", name, metric_type).unwrap();
+    if let Some(unit) = UNITS
+        .iter()
+        .find(|unit| name.strip_suffix(*unit).is_some_and(|n| n.ends_with('_')))
+    {
+        writeln!(buf, "# UNIT {} {}", name, unit).unwrap();
+    }
+}
+
+fn write_sample(
+    buf: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) {
+    buf.push_str(name);
+
+    let mut pairs: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|lp| (lp.get_name(), lp.get_value()))
+        .collect();
+    if let Some((k, ref v)) = extra_label {
+        pairs.push((k, v.as_str()));
+    }
+    write_labels(buf, &pairs);
+
+    write!(buf, " {}", format_float(value)).unwrap();
+    if let Some(exemplar) = exemplar {
+        write_exemplar(buf, exemplar);
+    }
+}
+
+fn write_exemplar(buf: &mut String, exemplar: &Exemplar) {
+    let mut pairs = vec![("trace_id", exemplar.trace_id())];
+    pairs.extend(exemplar.labels().iter().map(|t| (t.key(), t.value())));
+
+    buf.push_str(" # ");
+    write_labels(buf, &pairs);
+
+    let timestamp = exemplar
+        .timestamp()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0f64);
+    write!(buf, " {} {:.3}", format_float(exemplar.value()), timestamp).unwrap();
+}
+
+fn write_labels(buf: &mut String, pairs: &[(&str, &str)]) {
+    if pairs.is_empty() {
+        return;
+    }
+
+    buf.push('{');
+    for (idx, (k, v)) in pairs.iter().enumerate() {
+        if idx > 0 {
+            buf.push(',');
+        }
+        write!(buf, "{}=\"{}\"", k, escape_label_value(v)).unwrap();
+    }
+    buf.push('}');
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_owned()
+    } else if v == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        format!("{}", v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    use metriki_core::key::Tag;
+    use metriki_core::metrics::{Buckets, Exemplar};
+    use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
+    use metriki_core::MetricsRegistry;
+    use prometheus::proto::MetricFamily;
+
+    use super::{encode, Exemplars};
+    use crate::Collector;
+
+    fn collect(registry: &MetricsRegistry) -> (Vec<MetricFamily>, Exemplars) {
+        let reporting_options = ReportingOptions {
+            percentiles: vec![0.99],
+            ..Default::default()
+        };
+        let collector = Collector {
+            prefix: "",
+            reporting_options: &reporting_options,
+            export_rates: false,
+        };
+        let mut state = TemporalityState::new(Temporality::Cumulative);
+        let mut exemplars = Exemplars::default();
+        let families = collector
+            .collect(&registry.snapshots(), &mut state, &mut exemplars)
+            .unwrap();
+        (families, exemplars)
+    }
+
+    fn encode_registry(registry: &MetricsRegistry) -> String {
+        let (families, exemplars) = collect(registry);
+        encode(&families, &exemplars)
+    }
+
+    #[test]
+    fn test_encode_bucket_exemplars() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let latency = registry.bucketed_histogram_with_tags(
+            "latency",
+            vec![Tag::new("method", "GET")],
+            Buckets::new(vec![100.0]),
+        );
+        latency.update_with_exemplar(42.0, Exemplar::new("abc", vec![Tag::new("span", "1")]));
+        latency.update_with_exemplar(250.0, Exemplar::new("def", vec![]));
+        latency.update(20.0);
+
+        let text = encode_registry(&registry);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!("# TYPE latency histogram", lines[0]);
+        assert!(lines[1].starts_with(
+            "latency_bucket{method=\"GET\",le=\"100\"} 2 # {trace_id=\"abc\",span=\"1\"} 42 "
+        ));
+        assert!(lines[2]
+            .starts_with("latency_bucket{method=\"GET\",le=\"+Inf\"} 3 # {trace_id=\"def\"} 250 "));
+        assert_eq!("latency_sum{method=\"GET\"} 312", lines[3]);
+        assert_eq!("latency_count{method=\"GET\"} 3", lines[4]);
+    }
+
+    #[test]
+    fn test_encode_histogram() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let histogram = registry.bucketed_histogram("size", Buckets::new(vec![1.0, 10.0]));
+        histogram.update(0.5);
+        histogram.update(5.0);
+        histogram.update(50.0);
+
+        assert_eq!(
+            "# TYPE size histogram\n\
+             size_bucket{le=\"1\"} 1\n\
+             size_bucket{le=\"10\"} 2\n\
+             size_bucket{le=\"+Inf\"} 3\n\
+             size_sum 55.5\n\
+             size_count 3\n\
+             # EOF\n",
+            encode_registry(&registry)
+        );
+    }
+
+    #[test]
+    fn test_encode_counter_and_unit() {
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.monotonic_counter("requests").inc(3);
+        registry.gauge("heap_bytes", Box::new(|| 1024.0));
+
+        assert_eq!(
+            "# TYPE heap_bytes gauge\n\
+             # UNIT heap_bytes bytes\n\
+             heap_bytes 1024\n\
+             # TYPE requests counter\n\
+             requests_total 3\n\
+             # EOF\n",
+            encode_registry(&registry)
+        );
+    }
+
+    #[test]
+    fn test_encode_exemplars() {
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.monotonic_counter("requests").inc(3);
+        let histogram = registry.bucketed_histogram("size", Buckets::new(vec![1.0, 10.0]));
+        histogram.update_with_exemplar(5.0, Exemplar::new("abc", vec![Tag::new("span", "1")]));
+        let (families, mut exemplars) = collect(&registry);
+
+        // no metric records exemplars of counters yet
+        let exemplar = histogram
+            .snapshot()
+            .iter_exemplars()
+            .next()
+            .unwrap()
+            .1
+            .clone();
+        let requests = &families[0];
+        exemplars.insert(requests, &requests.get_metric()[0], None, exemplar.clone());
+
+        let timestamp = exemplar
+            .timestamp()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        assert_eq!(
+            format!(
+                "# TYPE requests counter\n\
+                 requests_total 3 # {{trace_id=\"abc\",span=\"1\"}} 5 {0:.3}\n\
+                 # TYPE size histogram\n\
+                 size_bucket{{le=\"1\"}} 0\n\
+                 size_bucket{{le=\"10\"}} 1 # {{trace_id=\"abc\",span=\"1\"}} 5 {0:.3}\n\
+                 size_bucket{{le=\"+Inf\"}} 1\n\
+                 size_sum 5\n\
+                 size_count 1\n\
+                 # EOF\n",
+                timestamp
+            ),
+            encode(&families, &exemplars)
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Push mode for short lived jobs, which may exit before any scrape.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use derive_builder::Builder;
+use log::warn;
+use metriki_core::reporter::{
+    error_type, ReportError, Reporter, ReporterHandle, ReporterMetrics, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
+use metriki_core::MetricsRegistry;
+use prometheus::{Encoder, TextEncoder, TEXT_FORMAT};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+
+use crate::openmetrics::Exemplars;
+use crate::Collector;
+
+/// HTTP method used to push metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PushMethod {
+    /// Replace all metrics of the grouping key.
+    #[default]
+    Put,
+    /// Replace only metrics with the same names as pushed ones.
+    Post,
+}
+
+/// Pushes metrics to a Prometheus Pushgateway.
+///
+/// Metrics are pushed to `{url}/metrics/job/{job}` followed by `grouping`
+/// labels, at start, every `interval_secs` and once more when stopped.
+#[derive(Builder)]
+pub struct PushGateway {
+    registry: Arc<MetricsRegistry>,
+    /// Base url of the Pushgateway, like `http://localhost:9091`.
+    #[builder(setter(into))]
+    url: String,
+    #[builder(setter(into))]
+    job: String,
+    /// Grouping labels besides `job`, like `instance`.
+    #[builder(default, setter)]
+    grouping: HashMap<String, String>,
+    #[builder(default = "30")]
+    interval_secs: u64,
+    #[builder(default)]
+    method: PushMethod,
+    /// User name for basic auth.
+    #[builder(default, setter(into, strip_option))]
+    username: Option<String>,
+    #[builder(default, setter(into, strip_option))]
+    password: Option<String>,
+    #[builder(default, setter(into))]
+    prefix: String,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// See `PrometheusExporterBuilder::export_rates`.
+    #[builder(default)]
+    export_rates: bool,
+    /// Registry to record metrics of pushes in, as `ReporterMetrics`
+    /// prefixed with `metriki.prometheus.push`.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    client: Option<Client>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+}
+
+impl PushGateway {
+    /// Start pushing in a background thread.
+    ///
+    /// Stop the returned handle before the job exits, to push final values.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval)
+            .on_error(|e| warn!("Failed to push metrics, {}", e))
+            .start()
+    }
+
+    fn push_url(&self) -> Result<Url, ReportError> {
+        let mut url = Url::parse(&self.url)?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| format!("Invalid pushgateway url {}", self.url))?;
+            segments.pop_if_empty().push("metrics");
+            segments.extend(label_segments("job", &self.job));
+            let mut grouping: Vec<_> = self.grouping.iter().collect();
+            grouping.sort();
+            for (name, value) in grouping {
+                segments.extend(label_segments(name, value));
+            }
+        }
+        Ok(url)
+    }
+
+    fn push(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(Temporality::Cumulative));
+        let collector = Collector {
+            prefix: &self.prefix,
+            reporting_options: &self.reporting_options,
+            export_rates: self.export_rates,
+        };
+        let families = collector.collect(snapshot, &mut state, &mut Exemplars::default());
+        self.state = Some(state);
+        let families = families?;
+
+        let mut body = Vec::new();
+        TextEncoder::new().encode(&families, &mut body)?;
+        if let Some(metrics) = self.metrics.as_ref() {
+            let points: usize = families.iter().map(|f| f.get_metric().len()).sum();
+            metrics.add_points(points as u64);
+            metrics.add_bytes(body.len() as u64);
+        }
+
+        let url = self.push_url()?;
+        let client = self.client.get_or_insert_with(Client::new);
+        let mut request = match self.method {
+            PushMethod::Put => client.put(url),
+            PushMethod::Post => client.post(url),
+        };
+        if let Some(username) = self.username.as_ref() {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        request
+            .header(CONTENT_TYPE, TEXT_FORMAT)
+            .body(body)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Path segments of a grouping label, base64 encoded when the value can't
+/// be a path segment as is.
+fn label_segments(name: &str, value: &str) -> [String; 2] {
+    if value.is_empty() {
+        [format!("{}@base64", name), "=".to_owned()]
+    } else if value.contains('/') {
+        [format!("{}@base64", name), URL_SAFE.encode(value)]
+    } else {
+        [name.to_owned(), value.to_owned()]
+    }
+}
+
+impl Reporter for PushGateway {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.prometheus.push"));
+        }
+        let start = Instant::now();
+        let result = self.push(snapshot);
+        if let Some(metrics) = self.metrics.as_ref() {
+            match &result {
+                Ok(()) => metrics.success(start.elapsed()),
+                Err(e) => metrics.failure(start.elapsed(), &error_type(e.as_ref())),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Arc;
+    use std::thread;
+
+    use metriki_core::MetricsRegistry;
+
+    use super::{PushGatewayBuilder, PushMethod};
+
+    struct Pushed {
+        request_line: String,
+        headers: Vec<String>,
+        body: String,
+    }
+
+    fn start_stub() -> (String, Receiver<Pushed>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_owned();
+                    if line.is_empty() {
+                        break;
+                    }
+                    headers.push(line.to_ascii_lowercase());
+                }
+                let length = headers
+                    .iter()
+                    .find_map(|h| h.strip_prefix("content-length: "))
+                    .map(|l| l.parse().unwrap())
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                let pushed = Pushed {
+                    request_line: request_line.trim_end().to_owned(),
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                };
+                if tx.send(pushed).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_push() {
+        let (url, rx) = start_stub();
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.counter("jobs").inc(3);
+
+        let mut grouping = HashMap::new();
+        grouping.insert("instance".to_owned(), "db/1".to_owned());
+        let handle = PushGatewayBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .job("batch")
+            .grouping(grouping)
+            .method(PushMethod::Post)
+            .username("user")
+            .password("pass")
+            .build()
+            .unwrap()
+            .start();
+
+        let pushed = rx.recv().unwrap();
+        assert_eq!(
+            "POST /metrics/job/batch/instance@base64/ZGIvMQ== HTTP/1.1",
+            pushed.request_line
+        );
+        assert!(pushed
+            .headers
+            .contains(&"authorization: basic dxnlcjpwyxnz".to_owned()));
+        assert!(pushed
+            .headers
+            .contains(&"content-type: text/plain; version=0.0.4".to_owned()));
+        assert!(pushed.body.contains("jobs 3\n"));
+
+        // final values are pushed when stopped
+        registry.counter("jobs").inc(2);
+        handle.stop();
+        handle.join();
+        let pushed = rx.try_iter().last().unwrap();
+        assert!(pushed.body.contains("jobs 5\n"));
+    }
+}
@@ -0,0 +1,603 @@
+//! Reporter for Prometheus compatible backends accepting remote write, like
+//! Mimir, VictoriaMetrics or Thanos receive.
+//!
+//! Metrics are sent as snappy compressed `WriteRequest` protobufs, with the
+//! same series names as scraped from `metriki-prometheus-exporter`: tags are
+//! mapped to labels, and histograms and timers to summaries with quantile
+//! series.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use derive_builder::Builder;
+use log::warn;
+use metriki_core::key::Key;
+use metriki_core::metrics::*;
+use metriki_core::reporter::{
+    DeliveryMetrics, Rejected, ReportError, Reporter, ReporterHandle, ReporterMetrics, RetryBuffer,
+    RetryOptions, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{
+    prometheus_label_name, prometheus_metric_name, ReportingOptions, Temporality, TemporalityState,
+};
+use metriki_core::MetricsRegistry;
+use prost::Message;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::StatusCode;
+
+pub mod proto;
+
+use proto::{Label, Sample, TimeSeries, WriteRequest};
+
+#[derive(Builder, Debug)]
+pub struct RemoteWriteReporter {
+    registry: Arc<MetricsRegistry>,
+    /// Url of the remote write endpoint, like `http://localhost:9009/api/v1/push`.
+    #[builder(setter(into))]
+    url: String,
+    #[builder(default = "30")]
+    interval_secs: u64,
+    /// Labels added to all series, like `job` and `instance`.
+    #[builder(default, setter)]
+    labels: HashMap<String, String>,
+    /// Extra http headers, like `X-Scope-OrgID` of Mimir.
+    #[builder(default, setter)]
+    headers: HashMap<String, String>,
+    /// User name for basic auth.
+    #[builder(default, setter(into, strip_option))]
+    username: Option<String>,
+    #[builder(default, setter(into, strip_option))]
+    password: Option<String>,
+    #[builder(default = "10")]
+    timeout_secs: u64,
+    #[builder(default, setter(into))]
+    prefix: String,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Max number of series sent in one request.
+    #[builder(default = "500")]
+    batch_size: usize,
+    /// Buffering and backoff of series failed to send.
+    #[builder(default)]
+    retry_options: RetryOptions,
+    /// Registry to record metrics of the reporter itself in. It can be the
+    /// reported registry itself.
+    ///
+    /// Reports are recorded as `ReporterMetrics` prefixed with
+    /// `metriki.remote_write.report`, and delivered series are counted by
+    /// monotonic counters `metriki.remote_write.series.{sent,failed,retried,dropped}`.
+    /// Series rejected with client errors are counted as failed and dropped.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    buffer: Option<RetryBuffer<TimeSeries>>,
+    #[builder(setter(skip))]
+    client: Option<Client>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+}
+
+fn system_time_millis() -> i64 {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH);
+    timestamp
+        .expect("System time earlier than UNIX_EPOCH")
+        .as_millis() as i64
+}
+
+impl RemoteWriteReporter {
+    /// Start reporting in a background thread.
+    ///
+    /// The reporter is stopped after a final report when the returned handle
+    /// is dropped, or `stop` is called.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval)
+            .on_error(|e| warn!("Failed to remote write, {}", e))
+            .start()
+    }
+
+    fn new_buffer(&self) -> RetryBuffer<TimeSeries> {
+        let buffer = RetryBuffer::new(self.retry_options.clone());
+        if let Some(registry) = self.self_metrics.as_ref() {
+            buffer.with_metrics(DeliveryMetrics::new(
+                registry,
+                "metriki.remote_write.series",
+            ))
+        } else {
+            buffer
+        }
+    }
+
+    fn send_buffer(&mut self, buffer: &mut RetryBuffer<TimeSeries>) -> Result<(), ReportError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        if self.client.is_none() {
+            self.client = Some(
+                Client::builder()
+                    // required by remote write 1.0
+                    .user_agent(concat!(
+                        "metriki-remote-write-reporter/",
+                        env!("CARGO_PKG_VERSION")
+                    ))
+                    .timeout(Duration::from_secs(self.timeout_secs))
+                    .build()?,
+            );
+        }
+        let client = self.client.as_ref().unwrap();
+        let metrics = self.metrics.as_ref();
+
+        buffer.send(self.batch_size, |batch| {
+            let request = WriteRequest {
+                timeseries: batch.to_vec(),
+            };
+            let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+            let bytes = body.len();
+
+            let mut request = client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+            for (name, value) in &self.headers {
+                request = request.header(name, value);
+            }
+            if let Some(username) = self.username.as_ref() {
+                request = request.basic_auth(username, self.password.as_ref());
+            }
+            let response = request.body(body).send()?;
+
+            let status = response.status();
+            if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                // rejected series, like out of order samples, would be
+                // rejected again on retries, so they are dropped
+                let error = format!(
+                    "Remote write rejected {} series with status {}, {}",
+                    batch.len(),
+                    status,
+                    response.text().unwrap_or_default()
+                );
+                return Err(Box::new(Rejected(error.into())));
+            } else if !status.is_success() {
+                return Err(format!("Remote write failed with status {}", status).into());
+            }
+
+            if let Some(metrics) = metrics {
+                metrics.add_bytes(bytes as u64);
+            }
+            Ok(())
+        })
+    }
+
+    fn report_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        state: &mut TemporalityState,
+        timestamp: i64,
+    ) -> Vec<TimeSeries> {
+        let mut series = Vec::new();
+        for (key, metric) in snapshot.iter() {
+            let name = key.key();
+            let mut add = |name: &str, extra: Option<(&str, String)>, value: f64| {
+                series.push(self.series(key, name, extra, value, timestamp));
+            };
+            match metric {
+                Metric::Counter(c) => add(name, None, c.value() as f64),
+                Metric::MonotonicCounter(c) => add(name, None, c.value() as f64),
+                Metric::Gauge(g) => add(name, None, g.value()),
+                Metric::UniqueCounter(c) => add(name, None, c.value() as f64),
+                Metric::Apdex(a) => {
+                    add(name, None, a.score());
+                    add_apdex_counts(&mut add, name, a);
+                }
+                Metric::ExponentialHistogram(h) => {
+                    let snapshot = h.snapshot();
+                    for q in &self.reporting_options.percentiles {
+                        add(
+                            name,
+                            Some(("quantile", q.to_string())),
+                            snapshot.quantile(*q),
+                        );
+                    }
+                    add(&format!("{}_sum", name), None, snapshot.sum());
+                    add(&format!("{}_count", name), None, snapshot.count() as f64);
+                }
+                Metric::BucketedHistogram(h) => {
+                    let snapshot = h.snapshot();
+                    let bucket = format!("{}_bucket", name);
+                    for (le, count) in snapshot.iter_cumulative() {
+                        let le = if le.is_finite() {
+                            le.to_string()
+                        } else {
+                            "+Inf".to_owned()
+                        };
+                        add(&bucket, Some(("le", le)), count as f64);
+                    }
+                    add(&format!("{}_sum", name), None, snapshot.sum());
+                    add(&format!("{}_count", name), None, snapshot.count() as f64);
+                }
+                Metric::Timer(t) => {
+                    let latency = t.latency();
+                    // completed samples only, like the sum
+                    let (count, sum) = state.histogram(key, &latency);
+                    for q in &self.reporting_options.percentiles {
+                        let value = latency.quantile(*q) as f64;
+                        add(name, Some(("quantile", q.to_string())), value);
+                    }
+                    add(&format!("{}_sum", name), None, sum as f64);
+                    add(&format!("{}_count", name), None, count as f64);
+                    if let Some(apdex) = t.apdex() {
+                        let name = format!("{}_apdex", name);
+                        add(&name, None, apdex.score());
+                        add_apdex_counts(&mut add, &name, apdex);
+                    }
+                }
+                Metric::Meter(m) => add(name, None, m.count() as f64),
+                Metric::Histogram(h) => {
+                    let snapshot = h.snapshot();
+                    let (count, sum) = state.histogram(key, &snapshot);
+                    for q in &self.reporting_options.percentiles {
+                        let value = snapshot.quantile(*q) as f64;
+                        add(name, Some(("quantile", q.to_string())), value);
+                    }
+                    add(&format!("{}_sum", name), None, sum as f64);
+                    add(&format!("{}_count", name), None, count as f64);
+                }
+            }
+        }
+        series
+    }
+
+    /// A series of one sample, labelled by `labels`, tags of the key and
+    /// `extra` in that order of precedence.
+    fn series(
+        &self,
+        key: &Key,
+        name: &str,
+        extra: Option<(&str, String)>,
+        value: f64,
+        timestamp: i64,
+    ) -> TimeSeries {
+        let mut labels = BTreeMap::new();
+        for (name, value) in &self.labels {
+            labels.insert(prometheus_label_name(name), value.clone());
+        }
+        for tag in key.tags() {
+            labels.insert(prometheus_label_name(tag.key()), tag.value().to_owned());
+        }
+        if let Some((name, value)) = extra {
+            labels.insert(name.to_owned(), value);
+        }
+        labels.insert(
+            "__name__".to_owned(),
+            prometheus_metric_name(&format!("{}{}", self.prefix, name)),
+        );
+
+        TimeSeries {
+            // sorted by name as required by the protocol
+            labels: labels
+                .into_iter()
+                .map(|(name, value)| Label { name, value })
+                .collect(),
+            samples: vec![Sample { value, timestamp }],
+        }
+    }
+}
+
+impl Reporter for RemoteWriteReporter {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.remote_write.report"));
+        }
+        let start = Instant::now();
+
+        // remote write backends expect cumulative counts, like scraped ones
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(Temporality::Cumulative));
+        let series = self.report_snapshot(snapshot, &mut state, system_time_millis());
+        self.state = Some(state);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.add_points(series.len() as u64);
+        }
+
+        let mut buffer = self.buffer.take().unwrap_or_else(|| self.new_buffer());
+        buffer.push(series);
+        let result = self.send_buffer(&mut buffer);
+        self.buffer = Some(buffer);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.record(start.elapsed(), &result);
+        }
+        result
+    }
+
+    fn report_final(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        // no later report to wait for backoff
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer.retry_now();
+        }
+        self.report(snapshot)
+    }
+}
+
+/// Apdex counts, so the score can be calculated over a range with `rate()`,
+/// and the threshold in milliseconds, named like the Prometheus exporter.
+fn add_apdex_counts<F>(add: &mut F, name: &str, apdex: &Apdex)
+where
+    F: FnMut(&str, Option<(&str, String)>, f64),
+{
+    let snapshot = apdex.snapshot();
+    add(
+        &format!("{}_satisfied", name),
+        None,
+        snapshot.satisfied() as f64,
+    );
+    add(
+        &format!("{}_tolerating", name),
+        None,
+        snapshot.tolerating() as f64,
+    );
+    add(
+        &format!("{}_frustrated", name),
+        None,
+        snapshot.frustrated() as f64,
+    );
+    let threshold = apdex.threshold().as_secs_f64() * 1000.0;
+    add(&format!("{}_threshold", name), None, threshold);
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use metriki_core::key::Tag;
+    use metriki_core::reporter::{Deferred, Rejected, Reporter, RetryOptions};
+    use metriki_core::MetricsRegistry;
+    use prost::Message;
+    use tiny_http::{Response, Server};
+
+    use super::proto::{TimeSeries, WriteRequest};
+    use super::RemoteWriteReporterBuilder;
+
+    /// Start a remote write receiver that fails first requests with
+    /// `failures` status codes, and returns its url and received requests.
+    fn stub_receiver(failures: &'static [u16]) -> (String, Arc<Mutex<Vec<WriteRequest>>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/push", server.server_addr());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_ref = requests.clone();
+
+        thread::spawn(move || {
+            for (i, mut req) in server.incoming_requests().enumerate() {
+                let header = |name: &'static str| {
+                    req.headers()
+                        .iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string())
+                };
+                assert_eq!(Some("snappy".to_owned()), header("Content-Encoding"));
+                assert!(header("User-Agent")
+                    .unwrap()
+                    .starts_with("metriki-remote-write-reporter/"));
+                assert_eq!(
+                    Some("application/x-protobuf".to_owned()),
+                    header("Content-Type")
+                );
+
+                let mut body = Vec::new();
+                req.as_reader().read_to_end(&mut body).unwrap();
+                let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                requests_ref
+                    .lock()
+                    .unwrap()
+                    .push(WriteRequest::decode(body.as_slice()).unwrap());
+
+                let status = failures.get(i).copied().unwrap_or(204);
+                req.respond(Response::empty(status)).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn find<'a>(series: &'a [TimeSeries], labels: &[(&str, &str)]) -> Option<&'a TimeSeries> {
+        series.iter().find(|s| {
+            labels.len() == s.labels.len()
+                && labels
+                    .iter()
+                    .zip(s.labels.iter())
+                    .all(|((name, value), label)| label.name == *name && label.value == *value)
+        })
+    }
+
+    #[test]
+    fn test_remote_write() {
+        let (url, requests) = stub_receiver(&[]);
+        let registry = Arc::new(MetricsRegistry::new());
+        registry
+            .counter_with_tags("requests", vec![Tag::new("method", "GET")])
+            .inc(3);
+        let histogram = registry.histogram("db.latency");
+        for v in 1..=100 {
+            histogram.update(v);
+        }
+        registry.monotonic_counter("http:requests").inc(2);
+        let timer = registry.timer("job.duration");
+        timer.enable_apdex(Duration::from_millis(10));
+        timer.update(Duration::from_millis(5));
+        let _in_flight = timer.start();
+
+        let mut labels = HashMap::new();
+        labels.insert("job".to_owned(), "batch".to_owned());
+        let mut reporter = RemoteWriteReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .labels(labels)
+            .build()
+            .unwrap();
+        reporter.report(&registry.snapshots()).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        let series = &requests[0].timeseries;
+
+        let requests = find(
+            series,
+            &[
+                ("__name__", "requests"),
+                ("job", "batch"),
+                ("method", "GET"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(3.0, requests.samples[0].value);
+        assert!(requests.samples[0].timestamp > 0);
+
+        let p99 = find(
+            series,
+            &[
+                ("__name__", "db_latency"),
+                ("job", "batch"),
+                ("quantile", "0.99"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(99.0, p99.samples[0].value);
+        let count = find(
+            series,
+            &[("__name__", "db_latency_count"), ("job", "batch")],
+        )
+        .unwrap();
+        assert_eq!(100.0, count.samples[0].value);
+        let count = find(
+            series,
+            &[("__name__", "job_duration_count"), ("job", "batch")],
+        )
+        .unwrap();
+        assert_eq!(1.0, count.samples[0].value);
+        let satisfied = find(
+            series,
+            &[
+                ("__name__", "job_duration_apdex_satisfied"),
+                ("job", "batch"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(1.0, satisfied.samples[0].value);
+        // colons of recording rule style names are kept
+        let counter = find(series, &[("__name__", "http:requests"), ("job", "batch")]).unwrap();
+        assert_eq!(2.0, counter.samples[0].value);
+    }
+
+    #[test]
+    fn test_retry_failed_writes() {
+        let (url, requests) = stub_receiver(&[503]);
+        let registry = Arc::new(MetricsRegistry::new());
+        let self_metrics = Arc::new(MetricsRegistry::new());
+
+        let mut reporter = RemoteWriteReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .retry_options(RetryOptions {
+                capacity: 10,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_secs(1),
+            })
+            .self_metrics(self_metrics.clone())
+            .build()
+            .unwrap();
+
+        registry.monotonic_counter("requests").inc(3);
+        assert!(reporter.report(&registry.snapshots()).is_err());
+
+        thread::sleep(Duration::from_millis(20));
+        reporter.report(&registry.snapshots()).unwrap();
+
+        // the failed series is written again with the new one
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(2, requests[1].timeseries.len());
+
+        let value = |name| self_metrics.monotonic_counter(name).value();
+        assert_eq!(2, value("metriki.remote_write.series.sent"));
+        assert_eq!(1, value("metriki.remote_write.series.failed"));
+        assert_eq!(1, value("metriki.remote_write.series.retried"));
+        assert_eq!(2, value("metriki.remote_write.report.points"));
+        assert!(value("metriki.remote_write.report.bytes") > 0);
+    }
+
+    #[test]
+    fn test_final_report_skips_backoff() {
+        let (url, requests) = stub_receiver(&[503]);
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.monotonic_counter("requests").inc(3);
+
+        let mut reporter = RemoteWriteReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .build()
+            .unwrap();
+        assert!(reporter.report(&registry.snapshots()).is_err());
+
+        // nothing is sent during backoff
+        let deferred = reporter.report(&registry.snapshots()).unwrap_err();
+        assert!(deferred.is::<Deferred>());
+        assert_eq!(1, requests.lock().unwrap().len());
+
+        reporter.report_final(&registry.snapshots()).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(3, requests[1].timeseries.len());
+    }
+
+    #[test]
+    fn test_drop_rejected_writes() {
+        let (url, requests) = stub_receiver(&[400]);
+        let registry = Arc::new(MetricsRegistry::new());
+        let self_metrics = Arc::new(MetricsRegistry::new());
+
+        let mut reporter = RemoteWriteReporterBuilder::default()
+            .registry(registry.clone())
+            .url(url)
+            .self_metrics(self_metrics.clone())
+            .build()
+            .unwrap();
+
+        registry.monotonic_counter("requests").inc(3);
+        let rejected = reporter.report(&registry.snapshots()).unwrap_err();
+        assert!(rejected.is::<Rejected>());
+
+        // the rejected series is not written again
+        reporter.report(&registry.snapshots()).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(1, requests[1].timeseries.len());
+
+        let value = |name| self_metrics.monotonic_counter(name).value();
+        assert_eq!(1, value("metriki.remote_write.series.sent"));
+        assert_eq!(1, value("metriki.remote_write.series.failed"));
+        assert_eq!(1, value("metriki.remote_write.series.dropped"));
+        let errors = self_metrics
+            .monotonic_counter_with_tags(
+                "metriki.remote_write.report.errors",
+                vec![Tag::new("type", "rejected")],
+            )
+            .value();
+        assert_eq!(1, errors);
+    }
+}
@@ -1,15 +1,21 @@
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use derive_builder::Builder;
 
 use lazy_static::lazy_static;
 use log::warn;
 use metriki_core::metrics::*;
+use metriki_core::reporter::{
+    DeliveryMetrics, ReportError, Reporter, ReporterHandle, ReporterMetrics, RetryBuffer,
+    RetryOptions, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
 use metriki_core::MetricsRegistry;
+use prost::Message;
 use rustmann::protos::riemann::Event;
 use rustmann::{EventBuilder, RiemannClient, RiemannClientOptionsBuilder};
-use tokio::time;
+use tokio::runtime::Runtime;
 
 lazy_static! {
     static ref THE_HOSTNAME: Option<String> = hostname::get()
@@ -28,6 +34,33 @@ pub struct RiemannReporter {
     interval_secs: u64,
     #[builder(default, setter)]
     tags: Vec<String>,
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Whether counts are sent as totals or changes since last report.
+    #[builder(default)]
+    temporality: Temporality,
+    /// Max number of events sent in one message.
+    #[builder(default = "100")]
+    batch_size: usize,
+    /// Buffering and backoff of events failed to send.
+    #[builder(default)]
+    retry_options: RetryOptions,
+    /// Registry to record metrics of the reporter itself in. It can be the
+    /// reported registry itself.
+    ///
+    /// Reports are recorded as `ReporterMetrics` prefixed with
+    /// `metriki.riemann.report`, and delivered events are counted by
+    /// monotonic counters `metriki.riemann.events.{sent,failed,retried,dropped}`.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    buffer: Option<RetryBuffer<Event>>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+    #[builder(setter(skip))]
+    runtime: Option<Runtime>,
 }
 
 fn system_time_millis() -> u128 {
@@ -47,36 +80,97 @@ impl RiemannReporter {
         RiemannClient::new(&riemann_options)
     }
 
-    pub fn start(self) {
-        tokio::spawn(async move {
-            loop {
-                let metrics = self.registry.snapshots();
-                let client = Arc::new(self.new_client());
-
-                let events: Vec<Event> = metrics
-                    .iter()
-                    .flat_map(|(key, metric)| match metric {
-                        Metric::Counter(c) => {
-                            self.report_counter(key.key(), c.as_ref()).into_iter()
-                        }
-                        Metric::Gauge(g) => self.report_gauge(key.key(), g.as_ref()).into_iter(),
-                        Metric::Timer(t) => self.report_timer(key.key(), t.as_ref()).into_iter(),
-                        Metric::Meter(m) => self.report_meter(key.key(), m.as_ref()).into_iter(),
-                        Metric::Histogram(h) => {
-                            self.report_histogram(key.key(), &h.snapshot()).into_iter()
-                        }
-                    })
-                    .collect();
-
-                if !events.is_empty() {
-                    if let Err(e) = client.send_events(events).await {
-                        warn!("Failed to write influxdb, {}", e);
-                    }
-                }
+    /// Start reporting in a background thread.
+    ///
+    /// The reporter is stopped after a final report when the returned handle
+    /// is dropped, or `stop` is called.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval)
+            .on_error(|e| warn!("Failed to write riemann, {}", e))
+            .start()
+    }
+
+    fn new_buffer(&self) -> RetryBuffer<Event> {
+        let buffer = RetryBuffer::new(self.retry_options.clone());
+        if let Some(registry) = self.self_metrics.as_ref() {
+            buffer.with_metrics(DeliveryMetrics::new(registry, "metriki.riemann.events"))
+        } else {
+            buffer
+        }
+    }
+
+    fn send_buffer(&mut self, buffer: &mut RetryBuffer<Event>) -> Result<(), ReportError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        if self.runtime.is_none() {
+            self.runtime = Some(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?,
+            );
+        }
+        let client = self.new_client();
+        let runtime = self.runtime.as_ref().unwrap();
+
+        let metrics = self.metrics.as_ref();
 
-                time::sleep(Duration::from_secs(self.interval_secs)).await;
+        buffer.send(self.batch_size, |batch| {
+            runtime.block_on(client.send_events(batch.to_vec()))?;
+            if let Some(metrics) = metrics {
+                let bytes: usize = batch.iter().map(|e| e.encoded_len()).sum();
+                metrics.add_bytes(bytes as u64);
             }
-        });
+            Ok(())
+        })
+    }
+
+    fn report_snapshot(&self, snapshot: &Snapshot, state: &mut TemporalityState) -> Vec<Event> {
+        snapshot
+            .iter()
+            .flat_map(|(key, metric)| match metric {
+                Metric::Counter(c) => self.report_counter(key.key(), c.as_ref()).into_iter(),
+                Metric::MonotonicCounter(c) => self
+                    .report_count(key.key(), state.count(key, c.value()))
+                    .into_iter(),
+                Metric::Gauge(g) => self.report_gauge(key.key(), g.as_ref()).into_iter(),
+                Metric::UniqueCounter(c) => self.report_count(key.key(), c.value()).into_iter(),
+                Metric::Apdex(a) => self
+                    .report_apdex(key.key(), &state.apdex(key, a.snapshot()))
+                    .into_iter(),
+                Metric::ExponentialHistogram(h) => {
+                    let snapshot = state.exponential(key, h.snapshot());
+                    self.report_exponential_histogram(key.key(), &snapshot)
+                        .into_iter()
+                }
+                Metric::BucketedHistogram(h) => {
+                    let snapshot = state.buckets(key, h.snapshot());
+                    self.report_bucketed_histogram(key.key(), &snapshot)
+                        .into_iter()
+                }
+                Metric::Timer(t) => {
+                    let latency = t.latency();
+                    let totals = state.histogram(key, &latency);
+                    let mut events = self.report_histogram(key.key(), &latency, totals);
+                    events.extend(self.report_meter(key.key(), t.rate()));
+                    if let Some(apdex) = t.apdex() {
+                        let snapshot = state.apdex(key, apdex.snapshot());
+                        events.extend(self.report_apdex(key.key(), &snapshot));
+                    }
+                    events.into_iter()
+                }
+                Metric::Meter(m) => self.report_meter(key.key(), m.as_ref()).into_iter(),
+                Metric::Histogram(h) => {
+                    let snapshot = h.snapshot();
+                    let totals = state.histogram(key, &snapshot);
+                    self.report_histogram(key.key(), &snapshot, totals)
+                        .into_iter()
+                }
+            })
+            .collect()
     }
 
     fn event(&self) -> EventBuilder {
@@ -94,20 +188,16 @@ impl RiemannReporter {
     }
 
     fn report_meter(&self, name: &str, meter: &Meter) -> Vec<Event> {
-        vec![
-            self.event()
-                .service(format!("{}.m1", name))
-                .metric_d(meter.m1_rate())
-                .build(),
-            self.event()
-                .service(format!("{}.m5", name))
-                .metric_d(meter.m5_rate())
-                .build(),
-            self.event()
-                .service(format!("{}.m15", name))
-                .metric_d(meter.m15_rate())
-                .build(),
-        ]
+        self.reporting_options
+            .rates
+            .iter()
+            .map(|rate| {
+                self.event()
+                    .service(format!("{}.{}", name, rate.name()))
+                    .metric_d(rate.value(meter))
+                    .build()
+            })
+            .collect()
     }
 
     fn report_gauge(&self, name: &str, gauge: &Gauge) -> Vec<Event> {
@@ -115,28 +205,25 @@ impl RiemannReporter {
         vec![self.event().service(name).metric_d(value).build()]
     }
 
-    fn report_histogram(&self, name: &str, snapshot: &HistogramSnapshot) -> Vec<Event> {
-        vec![
-            self.event()
-                .service(format!("{}.p50", name))
-                .metric_d(snapshot.quantile(0.5) as f64)
-                .build(),
-            self.event()
-                .service(format!("{}.p75", name))
-                .metric_d(snapshot.quantile(0.75) as f64)
-                .build(),
-            self.event()
-                .service(format!("{}.p90", name))
-                .metric_d(snapshot.quantile(0.9) as f64)
-                .build(),
-            self.event()
-                .service(format!("{}.p99", name))
-                .metric_d(snapshot.quantile(0.99) as f64)
-                .build(),
-            self.event()
-                .service(format!("{}.p999", name))
-                .metric_d(snapshot.quantile(0.999) as f64)
-                .build(),
+    fn report_histogram(
+        &self,
+        name: &str,
+        snapshot: &HistogramSnapshot,
+        totals: (u64, u64),
+    ) -> Vec<Event> {
+        let (count, sum) = totals;
+        let mut events: Vec<Event> = self
+            .reporting_options
+            .named_percentiles()
+            .map(|(pname, q)| {
+                self.event()
+                    .service(format!("{}.{}", name, pname))
+                    .metric_d(snapshot.quantile(q) as f64)
+                    .build()
+            })
+            .collect();
+
+        events.extend(vec![
             self.event()
                 .service(format!("{}.min", name))
                 .metric_d(snapshot.min() as f64)
@@ -149,66 +236,229 @@ impl RiemannReporter {
                 .service(format!("{}.mean", name))
                 .metric_d(snapshot.mean())
                 .build(),
-        ]
-    }
-
-    fn report_counter(&self, name: &str, c: &Counter) -> Vec<Event> {
-        vec![self
-            .event()
-            .service(name)
-            .metric_d(c.value() as f64)
-            .build()]
-    }
-
-    fn report_timer(&self, name: &str, t: &Timer) -> Vec<Event> {
-        let rate = t.rate();
-        let latency = t.latency();
-
-        vec![
-            self.event()
-                .service(format!("{}.p50", name))
-                .metric_d(latency.quantile(0.5) as f64)
-                .build(),
             self.event()
-                .service(format!("{}.p75", name))
-                .metric_d(latency.quantile(0.75) as f64)
+                .service(format!("{}.count", name))
+                .metric_d(count as f64)
                 .build(),
             self.event()
-                .service(format!("{}.p90", name))
-                .metric_d(latency.quantile(0.9) as f64)
+                .service(format!("{}.sum", name))
+                .metric_d(sum as f64)
                 .build(),
+        ]);
+        events
+    }
+
+    fn report_bucketed_histogram(
+        &self,
+        name: &str,
+        snapshot: &BucketedHistogramSnapshot,
+    ) -> Vec<Event> {
+        let mut events: Vec<Event> = snapshot
+            .iter_cumulative()
+            .map(|(le, count)| {
+                self.event()
+                    .service(format!("{}.le_{}", name, le))
+                    .metric_d(count as f64)
+                    .build()
+            })
+            .collect();
+
+        events.extend(vec![
             self.event()
-                .service(format!("{}.p99", name))
-                .metric_d(latency.quantile(0.99) as f64)
+                .service(format!("{}.count", name))
+                .metric_d(snapshot.count() as f64)
                 .build(),
             self.event()
-                .service(format!("{}.p999", name))
-                .metric_d(latency.quantile(0.999) as f64)
+                .service(format!("{}.sum", name))
+                .metric_d(snapshot.sum())
                 .build(),
+        ]);
+        events
+    }
+
+    fn report_exponential_histogram(
+        &self,
+        name: &str,
+        snapshot: &ExponentialHistogramSnapshot,
+    ) -> Vec<Event> {
+        let mut events: Vec<Event> = self
+            .reporting_options
+            .named_percentiles()
+            .map(|(pname, q)| {
+                self.event()
+                    .service(format!("{}.{}", name, pname))
+                    .metric_d(snapshot.quantile(q))
+                    .build()
+            })
+            .collect();
+
+        events.extend(vec![
             self.event()
-                .service(format!("{}.min", name))
-                .metric_d(latency.min() as f64)
+                .service(format!("{}.count", name))
+                .metric_d(snapshot.count() as f64)
                 .build(),
             self.event()
-                .service(format!("{}.max", name))
-                .metric_d(latency.max() as f64)
+                .service(format!("{}.sum", name))
+                .metric_d(snapshot.sum())
                 .build(),
+        ]);
+        events
+    }
+
+    fn report_apdex(&self, name: &str, snapshot: &ApdexSnapshot) -> Vec<Event> {
+        vec![
             self.event()
-                .service(format!("{}.mean", name))
-                .metric_d(latency.mean())
+                .service(format!("{}.apdex", name))
+                .metric_d(snapshot.score())
                 .build(),
             self.event()
-                .service(format!("{}.m1", name))
-                .metric_d(rate.m1_rate())
+                .service(format!("{}.satisfied", name))
+                .metric_d(snapshot.satisfied() as f64)
                 .build(),
             self.event()
-                .service(format!("{}.m5", name))
-                .metric_d(rate.m5_rate())
+                .service(format!("{}.tolerating", name))
+                .metric_d(snapshot.tolerating() as f64)
                 .build(),
             self.event()
-                .service(format!("{}.m15", name))
-                .metric_d(rate.m15_rate())
+                .service(format!("{}.frustrated", name))
+                .metric_d(snapshot.frustrated() as f64)
                 .build(),
         ]
     }
+
+    fn report_counter(&self, name: &str, c: &Counter) -> Vec<Event> {
+        vec![self
+            .event()
+            .service(name)
+            .metric_d(c.value() as f64)
+            .build()]
+    }
+
+    fn report_count(&self, name: &str, count: u64) -> Vec<Event> {
+        vec![self.event().service(name).metric_d(count as f64).build()]
+    }
+}
+
+impl Reporter for RiemannReporter {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.riemann.report"));
+        }
+        let start = Instant::now();
+
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(self.temporality));
+        let events = self.report_snapshot(snapshot, &mut state);
+        self.state = Some(state);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.add_points(events.len() as u64);
+        }
+
+        let mut buffer = self.buffer.take().unwrap_or_else(|| self.new_buffer());
+        buffer.push(events);
+        let result = self.send_buffer(&mut buffer);
+        self.buffer = Some(buffer);
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.record(start.elapsed(), &result);
+        }
+        result
+    }
+
+    fn report_final(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        // no later report to wait for backoff
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer.retry_now();
+        }
+        self.report(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use metriki_core::MetricsRegistry;
+    use prost::Message;
+    use rustmann::protos::riemann::Msg;
+
+    use super::RiemannReporterBuilder;
+
+    fn serve(mut stream: TcpStream, services: Arc<Mutex<Vec<String>>>) {
+        let mut len = [0u8; 4];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+
+            let msg = Msg::decode(buf.as_slice()).unwrap();
+            services
+                .lock()
+                .unwrap()
+                .extend(msg.events.into_iter().filter_map(|e| e.service));
+
+            let resp = Msg {
+                ok: Some(true),
+                ..Default::default()
+            }
+            .encode_to_vec();
+            stream
+                .write_all(&(resp.len() as u32).to_be_bytes())
+                .unwrap();
+            stream.write_all(&resp).unwrap();
+        }
+    }
+
+    /// Start a riemann server that accepts events, and returns its port and
+    /// services of received events.
+    fn stub_riemann() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let services = Arc::new(Mutex::new(Vec::new()));
+        let services_ref = services.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let services = services_ref.clone();
+                thread::spawn(move || serve(stream.unwrap(), services));
+            }
+        });
+
+        (port, services)
+    }
+
+    #[test]
+    fn test_final_report_on_drop() {
+        let (port, services) = stub_riemann();
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.counter("requests").inc(3);
+
+        let handle = RiemannReporterBuilder::default()
+            .registry(registry.clone())
+            .host("127.0.0.1")
+            .port(port)
+            .interval_secs(3600)
+            .build()
+            .unwrap()
+            .start();
+
+        // reported at start and on flush
+        handle.flush();
+        assert_eq!(2, services.lock().unwrap().len());
+
+        registry.gauge("pool.size", Box::new(|| 10.0));
+        drop(handle);
+
+        let services = services.lock().unwrap();
+        assert_eq!(4, services.len());
+        assert!(services.contains(&"pool.size".to_owned()));
+    }
 }
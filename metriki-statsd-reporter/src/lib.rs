@@ -1,14 +1,17 @@
 use std::collections::HashMap;
 use std::net::UdpSocket;
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use cadence::prelude::*;
 use cadence::{Metric as StatsdMetric, MetricBuilder, MetricError, StatsdClient, UdpMetricSink};
 use derive_builder::Builder;
 use log::warn;
 use metriki_core::metrics::*;
+use metriki_core::reporter::{
+    ReportError, Reporter, ReporterHandle, ReporterMetrics, ScheduledReporter, Snapshot,
+};
+use metriki_core::reporting::{ReportingOptions, Temporality, TemporalityState};
 use metriki_core::MetricsRegistry;
 
 /// Reporter for Statsd and Statsd protocol compatible sinks.
@@ -25,43 +28,82 @@ pub struct StatsdReporter {
     prefix: String,
     #[builder(default, setter)]
     tags: HashMap<String, String>,
-}
-
-fn statsd_client_error_handler(err: MetricError) {
-    warn!("Metriki statsd reporter error: {}", err);
+    #[builder(default)]
+    reporting_options: ReportingOptions,
+    /// Registry to record metrics of the reporter itself in, as
+    /// `ReporterMetrics` prefixed with `metriki.statsd.report`. It can be the
+    /// reported registry itself.
+    #[builder(default, setter(strip_option))]
+    self_metrics: Option<Arc<MetricsRegistry>>,
+    #[builder(setter(skip))]
+    state: Option<TemporalityState>,
+    #[builder(setter(skip))]
+    metrics: Option<ReporterMetrics>,
+    /// Last error of sending metrics in current report.
+    #[builder(setter(skip))]
+    send_error: Mutex<Option<MetricError>>,
 }
 
 impl StatsdReporter {
-    fn new_client(&self) -> StatsdClient {
+    fn new_client(&self) -> Result<StatsdClient, ReportError> {
         let host = (self.host.clone(), self.port);
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let sink = UdpMetricSink::from(host, socket).unwrap();
-        StatsdClient::builder(&self.prefix, sink)
-            .with_error_handler(statsd_client_error_handler)
-            .build()
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let sink = UdpMetricSink::from(host, socket)?;
+        Ok(StatsdClient::from_sink(&self.prefix, sink))
     }
 
-    pub fn start(self) {
-        let looper = move || loop {
-            let metrics = self.registry.snapshots();
-            let client = self.new_client();
+    /// Start reporting in a background thread.
+    ///
+    /// The reporter is stopped after a final report when the returned handle
+    /// is dropped, or `stop` is called.
+    pub fn start(self) -> ReporterHandle {
+        let registry = self.registry.clone();
+        let interval = Duration::from_secs(self.interval_secs);
+        ScheduledReporter::new(registry, self, interval)
+            .on_error(|e| warn!("Metriki statsd reporter error: {}", e))
+            .start()
+    }
 
-            for (key, metric) in metrics {
-                match metric {
-                    Metric::Counter(ref c) => self.report_counter(key.key(), c, &client),
-                    Metric::Gauge(ref g) => self.report_gauge(key.key(), g.as_ref(), &client),
-                    Metric::Timer(ref t) => self.report_timer(key.key(), t.as_ref(), &client),
-                    Metric::Meter(ref m) => self.report_meter(key.key(), m, &client),
-                    Metric::Histogram(ref h) => {
-                        self.report_histogram(key.key(), &h.snapshot(), &client)
+    fn report_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        client: &StatsdClient,
+        state: &mut TemporalityState,
+    ) {
+        for (key, metric) in snapshot {
+            match metric {
+                Metric::Counter(ref c) => self.report_counter(key.key(), c, client),
+                Metric::MonotonicCounter(ref c) => {
+                    let count = state.count(key, c.value());
+                    self.report_count(key.key(), count, client)
+                }
+                Metric::Gauge(ref g) => self.report_gauge(key.key(), g.as_ref(), client),
+                Metric::UniqueCounter(ref c) => {
+                    self.send(client.gauge_with_tags(key.key(), c.value() as f64))
+                }
+                Metric::Apdex(ref a) => {
+                    let snapshot = state.apdex(key, a.snapshot());
+                    self.report_apdex(key.key(), &snapshot, client)
+                }
+                Metric::ExponentialHistogram(ref h) => {
+                    let snapshot = state.exponential(key, h.snapshot());
+                    self.report_exponential_histogram(key.key(), &snapshot, client)
+                }
+                Metric::BucketedHistogram(ref h) => {
+                    let snapshot = state.buckets(key, h.snapshot());
+                    self.report_bucketed_histogram(key.key(), &snapshot, client)
+                }
+                Metric::Timer(ref t) => {
+                    self.report_timer(key.key(), t.as_ref(), client);
+                    if let Some(apdex) = t.apdex() {
+                        let snapshot = state.apdex(key, apdex.snapshot());
+                        self.report_apdex(key.key(), &snapshot, client)
                     }
                 }
+                Metric::Meter(ref m) => self.report_meter(key.key(), m, client),
+                Metric::Histogram(ref h) => self.report_histogram(key.key(), &h.snapshot(), client),
             }
-
-            thread::sleep(Duration::from_secs(self.interval_secs));
-        };
-
-        thread::spawn(looper);
+        }
     }
 
     fn send<'a, T>(&'a self, mut mb: MetricBuilder<'a, '_, T>)
@@ -72,14 +114,24 @@ impl StatsdReporter {
             mb = mb.with_tag(k, v);
         }
 
-        mb.send();
+        match mb.try_send() {
+            Ok(metric) => {
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.add_points(1);
+                    metrics.add_bytes(metric.as_metric_str().len() as u64);
+                }
+            }
+            Err(e) => *self.send_error.lock().unwrap() = Some(e),
+        }
     }
 
     fn report_meter(&self, name: &str, meter: &Meter, client: &StatsdClient) {
-        self.send(client.meter_with_tags(&format!("{}.m1_rate", name), meter.m1_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.m5_rate", name), meter.m5_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.m15_rate", name), meter.m15_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.mean_rate", name), meter.mean_rate() as u64));
+        for rate in self.reporting_options.rates.iter() {
+            self.send(client.meter_with_tags(
+                &format!("{}.{}", name, rate.rate_name()),
+                rate.value(meter) as u64,
+            ));
+        }
     }
 
     fn report_gauge(&self, name: &str, gauge: &Gauge, client: &StatsdClient) {
@@ -88,38 +140,198 @@ impl StatsdReporter {
     }
 
     fn report_histogram(&self, name: &str, snapshot: &HistogramSnapshot, client: &StatsdClient) {
-        self.send(client.histogram_with_tags(&format!("{}.p50", name), snapshot.quantile(0.5)));
-        self.send(client.histogram_with_tags(&format!("{}.p75", name), snapshot.quantile(0.75)));
-        self.send(client.histogram_with_tags(&format!("{}.p90", name), snapshot.quantile(0.9)));
-        self.send(client.histogram_with_tags(&format!("{}.p99", name), snapshot.quantile(0.99)));
-        self.send(client.histogram_with_tags(&format!("{}.p999", name), snapshot.quantile(0.999)));
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            self.send(
+                client.histogram_with_tags(&format!("{}.{}", name, pname), snapshot.quantile(q)),
+            );
+        }
         self.send(client.histogram_with_tags(&format!("{}.min", name), snapshot.min()));
         self.send(client.histogram_with_tags(&format!("{}.max", name), snapshot.max()));
         self.send(client.histogram_with_tags(&format!("{}.mean", name), snapshot.mean()));
         self.send(client.histogram_with_tags(&format!("{}.count", name), snapshot.count()));
+        self.send(client.histogram_with_tags(&format!("{}.sum", name), snapshot.sum()));
+    }
+
+    fn report_bucketed_histogram(
+        &self,
+        name: &str,
+        snapshot: &BucketedHistogramSnapshot,
+        client: &StatsdClient,
+    ) {
+        for (le, count) in snapshot.iter_cumulative() {
+            self.report_count(&format!("{}.{}", name, bucket_name(le)), count, client);
+        }
+        self.report_count(&format!("{}.count", name), snapshot.count(), client);
+        self.send(client.gauge_with_tags(&format!("{}.sum", name), snapshot.sum()));
+    }
+
+    fn report_exponential_histogram(
+        &self,
+        name: &str,
+        snapshot: &ExponentialHistogramSnapshot,
+        client: &StatsdClient,
+    ) {
+        for (pname, q) in self.reporting_options.named_percentiles() {
+            self.send(
+                client.histogram_with_tags(&format!("{}.{}", name, pname), snapshot.quantile(q)),
+            );
+        }
+        self.report_count(&format!("{}.count", name), snapshot.count(), client);
+        self.send(client.gauge_with_tags(&format!("{}.sum", name), snapshot.sum()));
+    }
+
+    fn report_apdex(&self, name: &str, snapshot: &ApdexSnapshot, client: &StatsdClient) {
+        // score of samples since last report
+        self.send(client.gauge_with_tags(&format!("{}.apdex", name), snapshot.score()));
+        self.report_count(&format!("{}.satisfied", name), snapshot.satisfied(), client);
+        self.report_count(
+            &format!("{}.tolerating", name),
+            snapshot.tolerating(),
+            client,
+        );
+        self.report_count(
+            &format!("{}.frustrated", name),
+            snapshot.frustrated(),
+            client,
+        );
     }
 
     fn report_counter(&self, name: &str, c: &Counter, client: &StatsdClient) {
-        self.send(client.count_with_tags(name, c.value()));
+        // counter may go down so it's sent as gauge
+        self.send(client.gauge_with_tags(name, c.value() as f64));
+    }
+
+    fn report_count(&self, name: &str, delta: u64, client: &StatsdClient) {
+        self.send(client.count_with_tags(name, delta as i64));
     }
 
     fn report_timer(&self, name: &str, t: &Timer, client: &StatsdClient) {
-        let rate = t.rate();
-        let latency = t.latency();
-
-        self.send(client.histogram_with_tags(&format!("{}.p50", name), latency.quantile(0.5)));
-        self.send(client.histogram_with_tags(&format!("{}.p75", name), latency.quantile(0.75)));
-        self.send(client.histogram_with_tags(&format!("{}.p90", name), latency.quantile(0.9)));
-        self.send(client.histogram_with_tags(&format!("{}.p99", name), latency.quantile(0.99)));
-        self.send(client.histogram_with_tags(&format!("{}.p999", name), latency.quantile(0.999)));
-        self.send(client.histogram_with_tags(&format!("{}.min", name), latency.min()));
-        self.send(client.histogram_with_tags(&format!("{}.max", name), latency.max()));
-        self.send(client.histogram_with_tags(&format!("{}.mean", name), latency.mean()));
-        self.send(client.histogram_with_tags(&format!("{}.count", name), latency.count()));
-
-        self.send(client.meter_with_tags(&format!("{}.m1_rate", name), rate.m1_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.m5_rate", name), rate.m5_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.m15_rate", name), rate.m15_rate() as u64));
-        self.send(client.meter_with_tags(&format!("{}.mean_rate", name), rate.mean_rate() as u64));
+        self.report_histogram(name, &t.latency(), client);
+        self.report_meter(name, t.rate(), client);
+    }
+
+    fn do_report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        let client = self.new_client()?;
+        // statsd server adds up counts it receives, so counts are sent as deltas
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| TemporalityState::new(Temporality::Delta));
+        self.report_snapshot(snapshot, &client, &mut state);
+        self.state = Some(state);
+
+        // metrics failed to send are skipped, and the last error is returned
+        match self.send_error.lock().unwrap().take() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Reporter for StatsdReporter {
+    fn report(&mut self, snapshot: &Snapshot) -> Result<(), ReportError> {
+        if self.metrics.is_none() {
+            self.metrics = self
+                .self_metrics
+                .clone()
+                .map(|registry| ReporterMetrics::new(registry, "metriki.statsd.report"));
+        }
+
+        let start = Instant::now();
+        let result = self.do_report(snapshot);
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.record(start.elapsed(), &result);
+        }
+        result
+    }
+}
+
+/// Name of a bucket by its upper bound, formatted like the Prometheus `le`
+/// label without characters that have special meaning in metric names, like
+/// `le_0_5` and `le_Inf`.
+fn bucket_name(le: f64) -> String {
+    if le == f64::INFINITY {
+        "le_Inf".to_owned()
+    } else {
+        format!("le_{}", le).replace('.', "_")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use metriki_core::metrics::Buckets;
+    use metriki_core::MetricsRegistry;
+
+    use super::StatsdReporterBuilder;
+
+    fn received(socket: &UdpSocket) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(len) = socket.recv(&mut buf) {
+            lines.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        lines
+    }
+
+    #[test]
+    fn test_report_deltas_and_final_flush() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        let registry = Arc::new(MetricsRegistry::new());
+        registry.monotonic_counter("requests").inc(5);
+
+        let handle = StatsdReporterBuilder::default()
+            .registry(registry.clone())
+            .host("127.0.0.1")
+            .port(socket.local_addr().unwrap().port())
+            .interval_secs(3600)
+            .build()
+            .unwrap()
+            .start();
+
+        handle.flush();
+        assert!(received(&socket).contains(&"requests:5|c".to_owned()));
+
+        registry.monotonic_counter("requests").inc(2);
+        handle.stop();
+        handle.join();
+        assert_eq!(vec!["requests:2|c".to_owned()], received(&socket));
+    }
+
+    #[test]
+    fn test_report_bucket_names() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        let registry = Arc::new(MetricsRegistry::new());
+        let histogram = registry.bucketed_histogram("size", Buckets::new(vec![0.5, 10.0]));
+        histogram.update(0.2);
+        histogram.update(20.0);
+
+        let handle = StatsdReporterBuilder::default()
+            .registry(registry.clone())
+            .host("127.0.0.1")
+            .port(socket.local_addr().unwrap().port())
+            .interval_secs(3600)
+            .build()
+            .unwrap()
+            .start();
+
+        handle.flush();
+        let lines = received(&socket);
+        assert!(lines.contains(&"size.le_0_5:1|c".to_owned()));
+        assert!(lines.contains(&"size.le_10:1|c".to_owned()));
+        assert!(lines.contains(&"size.le_Inf:2|c".to_owned()));
+        handle.stop();
+        handle.join();
     }
 }
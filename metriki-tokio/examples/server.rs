@@ -22,7 +22,8 @@ async fn main() {
     env_logger::init();
 
     let registry = MetricsRegistry::arc();
-    LogReporterBuilder::default()
+    // the reporter is stopped when the handle is dropped
+    let _reporter = LogReporterBuilder::default()
         .registry(registry.clone())
         .interval_secs(10)
         .build()
@@ -1,11 +1,13 @@
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use derive_builder::Builder;
-use futures::{FutureExt, TryFutureExt};
+use futures::FutureExt;
 use hyper::{Body, Request, Response};
-use metriki_core::metrics::TimerContextArc;
-use metriki_core::MetricsRegistry;
+use log::warn;
+use metriki_core::metrics::{Counter, Outcome, TimerContextArc};
+use metriki_core::{MetricsRegistry, TimerFamily};
 use tower_layer::Layer;
 use tower_service::Service;
 
@@ -16,9 +18,13 @@ use crate::common::ResultFuture;
 /// Current provided metrics:
 ///
 /// * Timer all requests: `metric_name.all`
+/// * Timers by outcome, `ok`, `err` or `cancelled`: `metric_name.outcome`, tagged with `outcome`.
+///   Responses with 5xx status are `err`.
+/// * Apdex of all requests, if `apdex_threshold` is set on the layer, tracked on
+///   `metric_name.all`. Errors and 5xx responses are frustrated.
 /// * Timers by request method: eg, `metric_name.GET`
 /// * Meters by response status code family: eg, `metric_name.2xx`
-/// * Inflight request counter: `metric_name.inflight`
+/// * Inflight request counter, reported as gauge: `metric_name.inflight`
 /// * Meter for unhandled error: `metric_name.error`
 ///
 #[cfg_attr(docsrs, doc(cfg(feature = "macros")))]
@@ -26,9 +32,26 @@ use crate::common::ResultFuture;
 pub struct HyperMetricsService<S> {
     registry: Arc<MetricsRegistry>,
     base_metric_name: String,
+    outcomes: Arc<TimerFamily>,
     inner: S,
 }
 
+/// Decrease inflight counter when the request is finished or cancelled.
+struct InflightGuard(Arc<Counter>);
+
+impl InflightGuard {
+    fn new(counter: Arc<Counter>) -> InflightGuard {
+        counter.inc(1);
+        InflightGuard(counter)
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.dec(1);
+    }
+}
+
 // A sample data structure of hyper request
 //
 // Request {
@@ -64,49 +87,44 @@ where
 
         let request_timer = registry.timer(&format!("{}.all", name));
         let method_timer = registry.timer(&format!("{}.{}", name, req.method().as_str()));
-        let request_timer_ctx = TimerContextArc::start(request_timer);
+        // timers are recorded as cancelled and inflight counter is decreased
+        // if the future is dropped before completion
+        let request_timer_ctx =
+            TimerContextArc::start(request_timer).with_outcomes(self.outcomes.clone());
         let method_timer_ctx = TimerContextArc::start(method_timer);
+        let inflight = InflightGuard::new(registry.counter(&format!("{}.inflight", name)));
 
-        registry.counter(&format!("{}.inflight", name)).inc(1);
+        let f = self.inner.call(req).map(move |resp| {
+            // timers
+            method_timer_ctx.stop();
 
-        // this is bad :(
-        let inner_registry_err = registry.clone();
-        let inner_name_err = name.clone();
+            match resp {
+                Ok(ref resp) => {
+                    if resp.status().is_server_error() {
+                        request_timer_ctx.stop_with_outcome(Outcome::Err);
+                    } else {
+                        request_timer_ctx.stop_with_outcome(Outcome::Ok);
+                    }
 
-        let f = self
-            .inner
-            .call(req)
-            .map(move |resp| {
-                // timers
-                request_timer_ctx.stop();
-                method_timer_ctx.stop();
-
-                // inflight request counter
-                registry.counter(&format!("{}.inflight", name)).dec(1);
-
-                if let Ok(ref resp) = resp {
                     // meters by status code family, 2xx, 3xx, 4xx and 5xx
                     let status_family = resp.status().as_u16() / 100;
                     registry
                         .meter(&format!("{}.{}xx", name, status_family))
                         .mark();
                 }
+                Err(_) => {
+                    request_timer_ctx.stop_with_outcome(Outcome::Err);
 
-                resp
-            })
-            .map_err(move |e| {
-                // error meter
-                inner_registry_err
-                    .meter(&format!("{}.error", inner_name_err))
-                    .mark();
+                    // error meter
+                    registry.meter(&format!("{}.error", name)).mark();
+                }
+            }
 
-                // inflight request counter
-                inner_registry_err
-                    .counter(&format!("{}.inflight", inner_name_err))
-                    .dec(1);
+            // inflight request counter
+            drop(inflight);
 
-                e
-            });
+            resp
+        });
 
         Box::pin(f)
     }
@@ -121,16 +139,39 @@ pub struct HyperMetricsLayer {
     registry: Arc<MetricsRegistry>,
     #[builder(setter(into), default = "\"requests\".to_owned()")]
     base_metric_name: String,
+    /// Track apdex of all requests with this threshold
+    #[builder(default, setter(strip_option))]
+    apdex_threshold: Option<Duration>,
 }
 
 impl<S> Layer<S> for HyperMetricsLayer {
     type Service = HyperMetricsService<S>;
 
     fn layer(&self, service: S) -> Self::Service {
+        let outcomes = self
+            .registry
+            .timer_family(&format!("{}.outcome", self.base_metric_name), &["outcome"]);
+        if let Some(threshold) = self.apdex_threshold {
+            let name = format!("{}.all", self.base_metric_name);
+            let timer = self.registry.timer(&name);
+            // the timer may be shared with other layers, or by an earlier
+            // call of this one, which is fine with the same threshold
+            if !timer.enable_apdex(threshold) {
+                let existing = timer.apdex().map(|a| a.threshold());
+                if existing != Some(threshold) {
+                    warn!(
+                        "Apdex of {} is already enabled with threshold {:?}, ignoring {:?}",
+                        name, existing, threshold
+                    );
+                }
+            }
+        }
+
         HyperMetricsService {
             registry: self.registry.clone(),
             inner: service,
             base_metric_name: self.base_metric_name.clone(),
+            outcomes: Arc::new(outcomes),
         }
     }
 }
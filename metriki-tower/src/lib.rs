@@ -9,9 +9,9 @@ use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use derive_builder::Builder;
-use futures::{FutureExt, TryFutureExt};
-use metriki_core::metrics::TimerContextArc;
-use metriki_core::MetricsRegistry;
+use futures::FutureExt;
+use metriki_core::metrics::{Outcome, TimerContextArc};
+use metriki_core::{MetricsRegistry, TimerFamily};
 use tower_layer::Layer;
 use tower_service::Service;
 
@@ -23,6 +23,7 @@ use common::ResultFuture;
 pub struct MetricsService<S> {
     registry: Arc<MetricsRegistry>,
     base_metric_name: String,
+    outcomes: Arc<TimerFamily>,
     inner: S,
 }
 
@@ -49,19 +50,18 @@ where
         let registry = self.registry.clone();
         let name = self.name();
         let timer = registry.timer(&name);
-        let timer_ctx = TimerContextArc::start(timer);
-
-        let f = self
-            .inner
-            .call(req)
-            .map(move |resp| {
-                timer_ctx.stop();
-                resp
-            })
-            .map_err(move |e| {
+        // recorded as cancelled if the future is dropped before completion
+        let timer_ctx = TimerContextArc::start(timer).with_outcomes(self.outcomes.clone());
+
+        let f = self.inner.call(req).map(move |resp| {
+            if resp.is_ok() {
+                timer_ctx.stop_with_outcome(Outcome::Ok);
+            } else {
+                timer_ctx.stop_with_outcome(Outcome::Err);
                 registry.meter(&format!("{}.error", name)).mark();
-                e
-            });
+            }
+            resp
+        });
 
         Box::pin(f)
     }
@@ -73,9 +73,11 @@ where
 /// exectuion of your service, by
 ///
 /// * A timer to measure qps and processing latency
+/// * Timers by outcome, `ok`, `err` or `cancelled`, tagged with `outcome`
 /// * A meter to measure error rate
 ///
 /// The timer name is provided with option `base_metric_name`, default to `requests`.
+/// The outcome timers are named as `{timer_name}.outcome`.
 /// The error meter is named as `{timer_name}.error`.
 ///
 #[derive(Builder, Debug, Clone)]
@@ -89,10 +91,15 @@ impl<S> Layer<S> for MetricsLayer {
     type Service = MetricsService<S>;
 
     fn layer(&self, service: S) -> Self::Service {
+        let outcomes = self
+            .registry
+            .timer_family(&format!("{}.outcome", self.base_metric_name), &["outcome"]);
+
         MetricsService {
             registry: self.registry.clone(),
             inner: service,
             base_metric_name: self.base_metric_name.clone(),
+            outcomes: Arc::new(outcomes),
         }
     }
 }
@@ -9,7 +9,8 @@ use tracing_subscriber::registry::Registry;
 
 fn main() {
     env_logger::init();
-    LogReporterBuilder::default()
+    // the reporter is stopped when the handle is dropped
+    let _reporter = LogReporterBuilder::default()
         .registry(global_registry())
         .interval_secs(2)
         .build()
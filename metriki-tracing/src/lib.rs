@@ -66,7 +66,7 @@ where
 
     fn on_exit(&self, _id: &Id, ctx: Context<'_, S>) {
         if let Some(span_ref) = ctx.lookup_current() {
-            if let Some(timer_ctx) = span_ref.extensions().get::<TimerContextArc>() {
+            if let Some(timer_ctx) = span_ref.extensions_mut().remove::<TimerContextArc>() {
                 timer_ctx.stop();
             }
         }